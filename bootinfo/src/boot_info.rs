@@ -1,43 +1,107 @@
 use core::slice;
 
-const MAX_MEMORY_MAP_ENTRIES: usize = 256;
-
+/// The firmware memory map, the entries live in the bootinfo area.
 #[derive(Debug)]
 pub struct MemoryMap {
+    entries: u64,
     num_entries: usize,
-    entries: [MemoryMapEntry; MAX_MEMORY_MAP_ENTRIES],
 }
 
 impl MemoryMap {
-    pub fn add_entry(&mut self, entry: MemoryMapEntry) -> Result<(), ()> {
-        if self.num_entries >= MAX_MEMORY_MAP_ENTRIES {
-            return Err(());
+    /// `entries` has to point at `num_entries` entries that stay around forever.
+    pub unsafe fn new(entries: u64, num_entries: usize) -> Self {
+        MemoryMap {
+            entries,
+            num_entries,
         }
-
-        self.entries[self.num_entries as usize] = entry;
-        self.num_entries += 1;
-
-        Ok(())
     }
-    
+
     pub fn entries(&self) -> &[MemoryMapEntry] {
-        &self.entries[0..(self.num_entries)]
+        if self.num_entries == 0 {
+            return &[];
+        }
+
+        unsafe { slice::from_raw_parts(self.entries as *const MemoryMapEntry, self.num_entries) }
     }
 }
 
 impl Default for MemoryMap {
     fn default() -> Self {
         MemoryMap {
+            entries: 0,
             num_entries: 0,
-            entries: [Default::default(); MAX_MEMORY_MAP_ENTRIES],
         }
     }
 }
 
+/// What a region of physical memory is used for.
+/// Mostly the UEFI memory types, plus what the bootloader allocated for the kernel.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum MemoryType {
     Unusable,
     Conventional,
+    Reserved,
+    LoaderCode,
+    LoaderData,
+    BootServicesCode,
+    BootServicesData,
+    RuntimeServicesCode,
+    RuntimeServicesData,
+    AcpiReclaimable,
+    AcpiNonVolatile,
+    Mmio,
+    MmioPortSpace,
+    PalCode,
+    Persistent,
+    /// The loaded kernel segments.
+    KernelImage,
+    /// The stack the kernel starts on.
+    BootStack,
+    /// The boot info, memory map and console font.
+    BootInfo,
+    /// The page tables the kernel starts with.
+    PageTables,
+}
+
+impl MemoryType {
+    pub const ALL: [MemoryType; 19] = [
+        MemoryType::Unusable,
+        MemoryType::Conventional,
+        MemoryType::Reserved,
+        MemoryType::LoaderCode,
+        MemoryType::LoaderData,
+        MemoryType::BootServicesCode,
+        MemoryType::BootServicesData,
+        MemoryType::RuntimeServicesCode,
+        MemoryType::RuntimeServicesData,
+        MemoryType::AcpiReclaimable,
+        MemoryType::AcpiNonVolatile,
+        MemoryType::Mmio,
+        MemoryType::MmioPortSpace,
+        MemoryType::PalCode,
+        MemoryType::Persistent,
+        MemoryType::KernelImage,
+        MemoryType::BootStack,
+        MemoryType::BootInfo,
+        MemoryType::PageTables,
+    ];
+
+    /// Free for the kernel to use from the start.
+    /// Boot services memory is no longer used once the bootloader exits boot services.
+    pub fn is_usable(&self) -> bool {
+        match self {
+            MemoryType::Conventional | MemoryType::BootServicesCode | MemoryType::BootServicesData => true,
+            _ => false,
+        }
+    }
+
+    /// Only needed until the kernel has taken over, after which it can be reclaimed.
+    pub fn is_reclaimable(&self) -> bool {
+        match self {
+            MemoryType::LoaderCode | MemoryType::LoaderData | MemoryType::AcpiReclaimable => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -45,16 +109,8 @@ pub struct MemoryMapEntry {
     pub start: u64,
     pub size: usize,
     pub memory_type: MemoryType,
-}
-
-impl Default for MemoryMapEntry {
-    fn default() -> Self {
-        MemoryMapEntry {
-            start: 0,
-            size: 0,
-            memory_type: MemoryType::Unusable,
-        }
-    }
+    /// The UEFI memory attributes (cacheability, runtime, etc).
+    pub attributes: u64,
 }
 
 #[derive(Debug, Default, Copy, Clone)]
@@ -81,6 +137,10 @@ pub struct BootInfo {
     pub frame_buffer: FrameBuffer,
     pub memory_map: MemoryMap,
     pub console_font: ConsoleFont,
+    /// Physical address of the ACPI 2.0 RSDP, if the firmware provides one.
+    pub rsdp_address: Option<u64>,
+    /// Physical address of the SMBIOS 3 entry point, if the firmware provides one.
+    pub smbios_address: Option<u64>,
 }
 
 #[derive(Debug, Default, Copy, Clone)]
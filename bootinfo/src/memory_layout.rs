@@ -24,6 +24,13 @@ pub const HEAP_BASE: u64 = BOOTINFO_TOP;
 pub const HEAP_SIZE: u64 = gibibyte(1);
 pub const HEAP_TOP: u64 = HEAP_BASE + HEAP_SIZE;
 
+//kernel stacks, device registers (local APIC, I/O APIC, etc) and anything else mapped after boot
+//every range handed out here starts with a guard page like STACK_GUARD
+pub const VMALLOC_BASE: u64 = HEAP_TOP;
+pub const VMALLOC_GUARD_SIZE: u64 = page(1);
+pub const VMALLOC_SIZE: u64 = gibibyte(64);
+pub const VMALLOC_TOP: u64 = VMALLOC_BASE + VMALLOC_SIZE;
+
 const fn page(num: u64) -> u64 {
     num * 0x1000
 }
@@ -12,6 +12,9 @@ use xmas_elf::{header, program, ElfFile};
 
 //TODO This needs a better name
 pub const BOOTLOADER_DATA: uefi::table::boot::MemoryType = uefi::table::boot::MemoryType::custom(0x80000000);
+pub const KERNEL_IMAGE: uefi::table::boot::MemoryType = uefi::table::boot::MemoryType::custom(0x80000001);
+pub const BOOT_STACK: uefi::table::boot::MemoryType = uefi::table::boot::MemoryType::custom(0x80000002);
+pub const PAGE_TABLES: uefi::table::boot::MemoryType = uefi::table::boot::MemoryType::custom(0x80000003);
 
 pub unsafe fn map_area_and_ignore<M, F>(
     mapper: &mut M,
@@ -70,7 +73,7 @@ where
                 let frame_addr = boot_services
                     .allocate_pages(
                         AllocateType::AnyPages,
-                        BOOTLOADER_DATA,
+                        KERNEL_IMAGE,
                         num_frames as _,
                     )
                     .expect_success("Could not allocate frames");
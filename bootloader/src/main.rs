@@ -10,17 +10,21 @@ mod memory;
 
 use core::mem::MaybeUninit;
 
-use bootinfo::{boot_info::{BootInfo, ConsoleFont, FrameBuffer, FrameBufferInfo, MemoryMapEntry}, memory_layout::PHYSMAP_BASE};
+use bootinfo::{boot_info::{self, BootInfo, ConsoleFont, FrameBuffer, FrameBufferInfo, MemoryMap, MemoryMapEntry}, memory_layout::PHYSMAP_BASE};
 use file::load_file;
-use load_kernel::{load_kernel, map_area_and_ignore, BOOTLOADER_DATA};
+use load_kernel::{load_kernel, map_area_and_ignore, BOOTLOADER_DATA, BOOT_STACK, KERNEL_IMAGE, PAGE_TABLES};
 use log::info;
 use uefi::{
     prelude::{entry, Boot, BootServices, Handle, Status, SystemTable},
     proto::console::gop::GraphicsOutput,
-    table::boot::{AllocateType, MemoryDescriptor, MemoryType},
-    ResultExt,
+    table::{boot::{AllocateType, MemoryDescriptor, MemoryType}, cfg::{ACPI2_GUID, SMBIOS3_GUID}},
+    Guid, ResultExt,
 };
 use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
     structures::paging::{
         mapper::MapperAllSizes, FrameAllocator, OffsetPageTable, Page, PageTable, PageTableFlags,
         PhysFrame, Size2MiB, Size4KiB,
@@ -90,7 +94,7 @@ where
     let len = font.len();
 
     let (page, num_frames) = bootinfo_allocator.allocate(len);
-    let frame = allocate_frames(st.boot_services(), num_frames);
+    let frame = allocate_frames(st.boot_services(), BOOTLOADER_DATA, num_frames);
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
 
     unsafe {
@@ -133,7 +137,7 @@ where
 
     let (boot_info_addr, num_frames) = bootinfo_allocator.allocate(mem::size_of::<BootInfo>());
 
-    let frame = allocate_frames(boot_services, num_frames);
+    let frame = allocate_frames(boot_services, BOOTLOADER_DATA, num_frames);
 
     unsafe {
         map_area_and_ignore(
@@ -155,10 +159,61 @@ where
     (boot_info, boot_info_addr)
 }
 
+/// Room for the kernel's copy of the memory map, which can only be filled in after exiting boot services.
+struct MemoryMapStorage {
+    page: Page,
+    entries: *mut MemoryMapEntry,
+    capacity: usize,
+}
+
+fn map_memorymap_storage<M, A>(
+    bootinfo_allocator: &mut BootInfoPageAllocator,
+    mapper: &mut M,
+    allocator: &mut A,
+    boot_services: &BootServices,
+) -> MemoryMapStorage
+where
+    M: MapperAllSizes,
+    A: FrameAllocator<Size4KiB>,
+{
+    use core::mem;
+
+    // Leave room for the descriptors that allocating this, and the UEFI memory map, adds.
+    let capacity = boot_services.memory_map_size() / mem::size_of::<MemoryDescriptor>() + 16;
+
+    let (page, num_frames) = bootinfo_allocator.allocate(capacity * mem::size_of::<MemoryMapEntry>());
+    let frame = allocate_frames(boot_services, BOOTLOADER_DATA, num_frames);
+
+    unsafe {
+        map_area_and_ignore(
+            mapper,
+            page,
+            frame,
+            num_frames,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            allocator,
+        )
+        .expect("Could not map memory map");
+    }
+
+    MemoryMapStorage {
+        page,
+        entries: frame.start_address().as_u64() as *mut MemoryMapEntry,
+        capacity,
+    }
+}
+
+fn find_config_table(st: &SystemTable<Boot>, guid: Guid) -> Option<u64> {
+    st.config_table()
+        .iter()
+        .find(|entry| entry.guid == guid)
+        .map(|entry| entry.address as u64)
+}
+
 fn allocate_kernel_page_table(boot_services: &BootServices) -> OffsetPageTable<'static> {
     let phys_offset = VirtAddr::new(0);
     let kernel_page_table_frame = boot_services
-        .allocate_pages(AllocateType::AnyPages, BOOTLOADER_DATA, 1)
+        .allocate_pages(AllocateType::AnyPages, PAGE_TABLES, 1)
         .expect_success("Could not allocate kernel page table");
 
     let addr = phys_offset + kernel_page_table_frame;
@@ -232,7 +287,7 @@ where
 {
     use bootinfo::memory_layout::{STACK_BASE, STACK_FRAMES, STACK_TOP};
 
-    let frame = allocate_frames(boot_services, STACK_FRAMES);
+    let frame = allocate_frames(boot_services, BOOT_STACK, STACK_FRAMES);
     let page: Page = Page::containing_address(VirtAddr::new(STACK_BASE));
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
@@ -263,8 +318,13 @@ fn context_switch(
     unreachable!();
 }
 
-//TODO enable nxe bit
-//TODO enable write protect bit
+/// Make `NO_EXECUTE` and read-only mappings count, before switching to the kernel page table.
+fn enable_protection() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
 
 #[entry]
 fn efi_main(image: Handle, st: SystemTable<Boot>) -> Status {
@@ -316,10 +376,21 @@ fn efi_main(image: Handle, st: SystemTable<Boot>) -> Status {
             image,
         );
 
+        boot_info.rsdp_address = find_config_table(&st, ACPI2_GUID);
+        boot_info.smbios_address = find_config_table(&st, SMBIOS3_GUID);
+
+        info!("RSDP at {:X?}, SMBIOS at {:X?}", boot_info.rsdp_address, boot_info.smbios_address);
+
         (boot_info, boot_info_addr.start_address().as_mut_ptr())
     };
 
     let stack = map_stack(&mut allocator, &mut kernel_page_table, st.boot_services());
+    let memory_map_storage = map_memorymap_storage(
+        &mut bootinfo_allocator,
+        &mut kernel_page_table,
+        &mut allocator,
+        st.boot_services(),
+    );
 
     {
         use core::mem;
@@ -342,9 +413,10 @@ fn efi_main(image: Handle, st: SystemTable<Boot>) -> Status {
 
         map_loader(memory_map.clone(), &mut allocator, &mut kernel_page_table);
         map_physmap(&mut allocator, &mut kernel_page_table, memory_map.clone());
-        map_memorymap(memory_map.clone(), boot_info);
+        map_memorymap(memory_map.clone(), &memory_map_storage, boot_info);
     }
 
+    enable_protection();
     context_switch(&mut kernel_page_table, entry, stack, boot_info_addr);
 }
 
@@ -375,15 +447,46 @@ where
     }
 }
 
-fn map_memorymap<I>(memory_map: I, boot_info: &mut BootInfo) where I: ExactSizeIterator<Item = &'static MemoryDescriptor> + Clone, {
-    for entry in memory_map {
-        // Skip everything that's not marked as CONVENTIONAl for now
-        if entry.ty == MemoryType::CONVENTIONAL {
-            boot_info.memory_map.add_entry(MemoryMapEntry {
-                memory_type: bootinfo::boot_info::MemoryType::Conventional,
+fn memory_type(ty: MemoryType) -> boot_info::MemoryType {
+    use boot_info::MemoryType as Type;
+
+    match ty {
+        MemoryType::CONVENTIONAL => Type::Conventional,
+        MemoryType::UNUSABLE => Type::Unusable,
+        MemoryType::LOADER_CODE => Type::LoaderCode,
+        MemoryType::LOADER_DATA => Type::LoaderData,
+        MemoryType::BOOT_SERVICES_CODE => Type::BootServicesCode,
+        MemoryType::BOOT_SERVICES_DATA => Type::BootServicesData,
+        MemoryType::RUNTIME_SERVICES_CODE => Type::RuntimeServicesCode,
+        MemoryType::RUNTIME_SERVICES_DATA => Type::RuntimeServicesData,
+        MemoryType::ACPI_RECLAIM => Type::AcpiReclaimable,
+        MemoryType::ACPI_NON_VOLATILE => Type::AcpiNonVolatile,
+        MemoryType::MMIO => Type::Mmio,
+        MemoryType::MMIO_PORT_SPACE => Type::MmioPortSpace,
+        MemoryType::PAL_CODE => Type::PalCode,
+        MemoryType::PERSISTENT_MEMORY => Type::Persistent,
+        KERNEL_IMAGE => Type::KernelImage,
+        BOOT_STACK => Type::BootStack,
+        BOOTLOADER_DATA => Type::BootInfo,
+        PAGE_TABLES => Type::PageTables,
+        _ => Type::Reserved,
+    }
+}
+
+fn map_memorymap<I>(memory_map: I, storage: &MemoryMapStorage, boot_info: &mut BootInfo) where I: ExactSizeIterator<Item = &'static MemoryDescriptor> + Clone, {
+    let num_entries = memory_map.len();
+    assert!(num_entries <= storage.capacity, "Memory map does not fit");
+
+    for (index, entry) in memory_map.enumerate() {
+        unsafe {
+            storage.entries.add(index).write(MemoryMapEntry {
+                memory_type: memory_type(entry.ty),
                 start: entry.phys_start,
                 size: (entry.page_count * Page::<Size4KiB>::SIZE) as _,
-            }).expect("Could not map memorymap");
+                attributes: entry.att.bits(),
+            });
         }
     }
-}
\ No newline at end of file
+
+    boot_info.memory_map = unsafe { MemoryMap::new(storage.page.start_address().as_u64(), num_entries) };
+}
@@ -6,7 +6,7 @@ use x86_64::{PhysAddr, VirtAddr, align_up, structures::paging::{FrameAllocator,
 use x86_64::structures::paging::Size4KiB;
 use x86_64::structures::paging::PhysFrame;
 
-use crate::load_kernel::BOOTLOADER_DATA;
+use crate::load_kernel::PAGE_TABLES;
 
 #[derive(Debug)]
 pub struct BootFrameAllocator {
@@ -16,8 +16,7 @@ pub struct BootFrameAllocator {
 
 impl BootFrameAllocator {
     pub fn new(boot_services: &BootServices, num_frames: usize) -> BootFrameAllocator {
-        //TODO Use different MemoryType?
-        let frames_addr = boot_services.allocate_pages(AllocateType::AnyPages, MemoryType::RUNTIME_SERVICES_DATA, num_frames).expect_success("Could not allocate boot frames");
+        let frames_addr = boot_services.allocate_pages(AllocateType::AnyPages, PAGE_TABLES, num_frames).expect_success("Could not allocate boot frames");
 
         let next: PhysFrame = PhysFrame::containing_address(PhysAddr::new(frames_addr));
         let end: PhysFrame = next + num_frames as _;
@@ -66,9 +65,9 @@ impl BootInfoPageAllocator {
     }
 }
 
-pub fn allocate_frames(boot_services: &BootServices, num_frames: u64) -> PhysFrame {
+pub fn allocate_frames(boot_services: &BootServices, memory_type: MemoryType, num_frames: u64) -> PhysFrame {
     let frame = boot_services
-        .allocate_pages(AllocateType::AnyPages, BOOTLOADER_DATA, num_frames as _)
+        .allocate_pages(AllocateType::AnyPages, memory_type, num_frames as _)
         .expect_success("Could not allocate memory for boot info");
 
     PhysFrame::containing_address(PhysAddr::new(frame))
@@ -0,0 +1,135 @@
+#![no_std]
+
+use core::fmt;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+/// A UTC calendar date and time of day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u32,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC. Dates before that end up at zero.
+    pub fn to_unix(self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let seconds = days * SECONDS_PER_DAY as i64
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64;
+
+        seconds.max(0) as u64
+    }
+
+    pub fn from_unix(timestamp: u64) -> DateTime {
+        let (year, month, day) = civil_from_days((timestamp / SECONDS_PER_DAY) as i64);
+        let seconds = timestamp % SECONDS_PER_DAY;
+
+        DateTime {
+            year: year as u32,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// Both conversions work on years that start in March, so the leap day is at the end.
+// See http://howardhinnant.github.io/date_algorithms.html
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: u32, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime { year, month, day, hour, minute, second }
+    }
+
+    fn round_trip(date: DateTime, timestamp: u64) {
+        assert_eq!(date.to_unix(), timestamp, "{}", date);
+        assert_eq!(DateTime::from_unix(timestamp), date);
+    }
+
+    #[test]
+    fn epoch() {
+        round_trip(date(1970, 1, 1, 0, 0, 0), 0);
+    }
+
+    #[test]
+    fn leap_days() {
+        round_trip(date(1972, 2, 29, 0, 0, 0), 68169600);
+        round_trip(date(2024, 2, 29, 0, 0, 0), 1709164800);
+    }
+
+    #[test]
+    fn leap_day_in_2000() {
+        // Divisible by 400, so a leap year after all.
+        round_trip(date(2000, 2, 29, 0, 0, 0), 951782400);
+        round_trip(date(2000, 3, 1, 0, 0, 0), 951868800);
+    }
+
+    #[test]
+    fn no_leap_day_in_2100() {
+        round_trip(date(2100, 2, 28, 0, 0, 0), 4107456000);
+        round_trip(date(2100, 3, 1, 0, 0, 0), 4107542400);
+    }
+
+    #[test]
+    fn time_of_day() {
+        round_trip(date(2038, 1, 19, 3, 14, 8), 2147483648);
+    }
+
+    #[test]
+    fn before_epoch() {
+        assert_eq!(date(1969, 12, 31, 23, 59, 59).to_unix(), 0);
+    }
+
+    #[test]
+    fn every_day() {
+        for days in 0..200 * 366 {
+            let timestamp = days * SECONDS_PER_DAY + 12 * 3600;
+            assert_eq!(DateTime::from_unix(timestamp).to_unix(), timestamp);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use x86_64::{PhysAddr, instructions::port::Port};
+
+use crate::memory::{CacheMode, MapError, mmio};
+
+use super::{AddressSpace, GenericAddress, SdtHeader, find_table, read_u16, read_u32, read_u64};
+
+const SIGNATURE: &[u8; 4] = b"FACP";
+
+const FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+// Offsets from the start of the table
+const DSDT: usize = 40;
+const SCI_INT: usize = 46;
+const SMI_CMD: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const ACPI_DISABLE: usize = 53;
+const PM1A_EVT_BLK: usize = 56;
+const PM1B_EVT_BLK: usize = 60;
+const PM1A_CNT_BLK: usize = 64;
+const PM1B_CNT_BLK: usize = 68;
+const PM_TMR_BLK: usize = 76;
+const PM1_EVT_LEN: usize = 88;
+const PM1_CNT_LEN: usize = 89;
+const CENTURY: usize = 108;
+const FLAGS: usize = 112;
+const RESET_REG: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+const X_PM1A_EVT_BLK: usize = 148;
+const X_PM1B_EVT_BLK: usize = 160;
+const X_PM1A_CNT_BLK: usize = 172;
+const X_PM1B_CNT_BLK: usize = 184;
+const X_PM_TMR_BLK: usize = 208;
+
+/// The smallest FADT we understand, as defined by ACPI 1.0.
+const MIN_LENGTH: usize = 116;
+
+// Only `reset` returns this, and nothing resets the system yet.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ResetError {
+    NotSupported,
+    UnsupportedAddressSpace(AddressSpace),
+    Map(MapError),
+}
+
+/// A fixed power management register block.
+#[derive(Debug, Clone, Copy)]
+pub struct PmBlock {
+    pub address: GenericAddress,
+    #[allow(dead_code)]
+    pub length: u8,
+}
+
+/// The Fixed ACPI Description Table.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    table: &'static SdtHeader,
+}
+
+impl Fadt {
+    pub fn get() -> Option<Self> {
+        find_table(SIGNATURE).and_then(Self::from_table)
+    }
+
+    /// Use `table` as the FADT, if that is what it is.
+    pub fn from_table(table: &'static SdtHeader) -> Option<Self> {
+        if &table.signature != SIGNATURE || table.as_bytes().len() < MIN_LENGTH {
+            return None;
+        }
+
+        Some(Fadt { table })
+    }
+
+    fn bytes(&self) -> &'static [u8] {
+        self.table.as_bytes()
+    }
+
+    fn flags(&self) -> u32 {
+        read_u32(self.bytes(), FLAGS)
+    }
+
+    /// Prefer the 64-bit X_ block, fall back to the legacy 32-bit I/O port.
+    fn block(&self, extended: usize, legacy: usize, length: u8) -> Option<PmBlock> {
+        let address = GenericAddress::parse(self.bytes(), extended)
+            .filter(|address| address.address != 0)
+            .or_else(|| {
+                let port = read_u32(self.bytes(), legacy);
+                if port == 0 {
+                    return None;
+                }
+
+                Some(GenericAddress {
+                    address_space: AddressSpace::SystemIo,
+                    bit_width: length.saturating_mul(8),
+                    bit_offset: 0,
+                    access_size: 0,
+                    address: port as u64,
+                })
+            })?;
+
+        Some(PmBlock { address, length })
+    }
+
+    pub fn dsdt_address(&self) -> u64 {
+        match self.bytes().get(X_DSDT..X_DSDT + 8) {
+            Some(_) if read_u64(self.bytes(), X_DSDT) != 0 => read_u64(self.bytes(), X_DSDT),
+            _ => read_u32(self.bytes(), DSDT) as u64,
+        }
+    }
+
+    /// The CMOS RAM index of the RTC century register, if there is one.
+    pub fn rtc_century_register(&self) -> Option<u8> {
+        match self.bytes()[CENTURY] {
+            0 => None,
+            register => Some(register),
+        }
+    }
+
+    /// The GSI the System Control Interrupt is wired to.
+    pub fn sci_interrupt(&self) -> u16 {
+        read_u16(self.bytes(), SCI_INT)
+    }
+
+    // Switching to ACPI mode and the PM1 blocks are for the power management work to come.
+    /// The port to write `acpi_enable` or `acpi_disable` to, 0 if ACPI is always on.
+    #[allow(dead_code)]
+    pub fn smi_command_port(&self) -> u32 {
+        read_u32(self.bytes(), SMI_CMD)
+    }
+
+    #[allow(dead_code)]
+    pub fn acpi_enable(&self) -> u8 {
+        self.bytes()[ACPI_ENABLE]
+    }
+
+    #[allow(dead_code)]
+    pub fn acpi_disable(&self) -> u8 {
+        self.bytes()[ACPI_DISABLE]
+    }
+
+    #[allow(dead_code)]
+    pub fn pm1a_event_block(&self) -> Option<PmBlock> {
+        self.block(X_PM1A_EVT_BLK, PM1A_EVT_BLK, self.bytes()[PM1_EVT_LEN])
+    }
+
+    #[allow(dead_code)]
+    pub fn pm1b_event_block(&self) -> Option<PmBlock> {
+        self.block(X_PM1B_EVT_BLK, PM1B_EVT_BLK, self.bytes()[PM1_EVT_LEN])
+    }
+
+    #[allow(dead_code)]
+    pub fn pm1a_control_block(&self) -> Option<PmBlock> {
+        self.block(X_PM1A_CNT_BLK, PM1A_CNT_BLK, self.bytes()[PM1_CNT_LEN])
+    }
+
+    #[allow(dead_code)]
+    pub fn pm1b_control_block(&self) -> Option<PmBlock> {
+        self.block(X_PM1B_CNT_BLK, PM1B_CNT_BLK, self.bytes()[PM1_CNT_LEN])
+    }
+
+    /// The ACPI power management timer, running at 3.579545 MHz.
+    pub fn pm_timer_block(&self) -> Option<PmBlock> {
+        self.block(X_PM_TMR_BLK, PM_TMR_BLK, 4)
+    }
+
+    /// Whether the power management timer is 32 bits wide instead of 24.
+    pub fn pm_timer_is_32bit(&self) -> bool {
+        self.flags() & FLAG_TMR_VAL_EXT != 0
+    }
+
+    /// The register to write the reset value to, to reset the system.
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        if self.flags() & FLAG_RESET_REG_SUP == 0 {
+            return None;
+        }
+
+        let register = GenericAddress::parse(self.bytes(), RESET_REG)?;
+        let value = *self.bytes().get(RESET_VALUE)?;
+
+        Some((register, value))
+    }
+
+    /// Reset the system through the reset register. Returns if the reset didn't take.
+    #[allow(dead_code)]
+    pub fn reset(&self) -> Result<(), ResetError> {
+        let (register, value) = self.reset_register().ok_or(ResetError::NotSupported)?;
+
+        match register.address_space {
+            AddressSpace::SystemIo => unsafe {
+                Port::<u8>::new(register.address as u16).write(value);
+            },
+            AddressSpace::SystemMemory => {
+                let mmio = mmio::map(PhysAddr::new(register.address), 1, CacheMode::Uncached).map_err(ResetError::Map)?;
+                unsafe {
+                    core::ptr::write_volatile(mmio.addr().as_mut_ptr::<u8>(), value);
+                }
+            },
+            other => return Err(ResetError::UnsupportedAddressSpace(other)),
+        }
+
+        Ok(())
+    }
+}
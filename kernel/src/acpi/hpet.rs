@@ -0,0 +1,38 @@
+use super::{GenericAddress, SdtHeader, find_table, read_u32};
+
+const SIGNATURE: &[u8; 4] = b"HPET";
+
+// Offsets from the start of the table
+const EVENT_TIMER_BLOCK_ID: usize = 36;
+const BASE_ADDRESS: usize = 40;
+const LENGTH: usize = 56;
+
+/// The HPET Description Table.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    table: &'static SdtHeader,
+}
+
+impl Hpet {
+    pub fn get() -> Option<Self> {
+        let table = find_table(SIGNATURE)?;
+
+        if table.as_bytes().len() < LENGTH {
+            return None;
+        }
+
+        Some(Hpet { table })
+    }
+
+    fn event_timer_block_id(&self) -> u32 {
+        read_u32(self.table.as_bytes(), EVENT_TIMER_BLOCK_ID)
+    }
+
+    pub fn counter_is_64bit(&self) -> bool {
+        self.event_timer_block_id() & (1 << 13) != 0
+    }
+
+    pub fn base_address(&self) -> GenericAddress {
+        GenericAddress::parse(self.table.as_bytes(), BASE_ADDRESS).unwrap()
+    }
+}
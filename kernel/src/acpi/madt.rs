@@ -0,0 +1,178 @@
+use x86_64::PhysAddr;
+
+use crate::apic::{IoApicInfo, IrqOverride};
+
+use super::{SdtHeader, find_table, read_u16, read_u32, read_u64};
+
+const SIGNATURE: &[u8; 4] = b"APIC";
+
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MASK: u16 = 0b11 << 2;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+// Offsets from the start of the table
+const ENTRIES: usize = 44;
+
+// Not every field is used, but they are all parsed so the entries are complete.
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    LocalApic {
+        #[allow(dead_code)]
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    InterruptOverride { bus: u8, irq: u8, gsi: u32, flags: u16 },
+    #[allow(dead_code)]
+    LocalApicNmi { processor_id: u8, flags: u16, lint: u8 },
+    #[allow(dead_code)]
+    LocalApicAddressOverride { address: u64 },
+    LocalX2Apic {
+        x2apic_id: u32,
+        flags: u32,
+        #[allow(dead_code)]
+        processor_uid: u32,
+    },
+    #[allow(dead_code)]
+    Unknown { entry_type: u8 },
+}
+
+/// A processor as described by the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub apic_id: u32,
+    /// Disabled processors may still be brought online later, if they are online capable.
+    pub enabled: bool,
+}
+
+/// The Multiple APIC Description Table.
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    table: &'static SdtHeader,
+}
+
+impl Madt {
+    pub fn get() -> Option<Self> {
+        let table = find_table(SIGNATURE)?;
+
+        if table.as_bytes().len() < ENTRIES {
+            return None;
+        }
+
+        Some(Madt { table })
+    }
+
+    pub fn entries(&self) -> MadtEntries {
+        MadtEntries {
+            bytes: &self.table.as_bytes()[ENTRIES..],
+        }
+    }
+
+    /// Every processor that is either enabled, or can be brought online.
+    pub fn processors(&self) -> impl Iterator<Item = Processor> {
+        self.entries().filter_map(|entry| {
+            let (apic_id, flags) = match entry {
+                MadtEntry::LocalApic { apic_id, flags, .. } => (apic_id as u32, flags),
+                MadtEntry::LocalX2Apic { x2apic_id, flags, .. } => (x2apic_id, flags),
+                _ => return None,
+            };
+
+            if flags & (PROCESSOR_ENABLED | PROCESSOR_ONLINE_CAPABLE) == 0 {
+                return None;
+            }
+
+            Some(Processor {
+                apic_id,
+                enabled: flags & PROCESSOR_ENABLED != 0,
+            })
+        })
+    }
+
+    pub fn io_apics(&self) -> impl Iterator<Item = IoApicInfo> {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::IoApic { id, address, gsi_base } => Some(IoApicInfo {
+                id,
+                address: PhysAddr::new(address as u64),
+                gsi_base,
+            }),
+            _ => None,
+        })
+    }
+
+    /// ISA interrupts that are not identity mapped to global system interrupts.
+    pub fn interrupt_overrides(&self) -> impl Iterator<Item = IrqOverride> {
+        self.entries().filter_map(|entry| match entry {
+            // Bus 0 is ISA, nothing else is defined
+            MadtEntry::InterruptOverride { bus: 0, irq, gsi, flags } => Some(IrqOverride {
+                irq,
+                gsi,
+                active_low: flags & POLARITY_MASK == POLARITY_ACTIVE_LOW,
+                level_triggered: flags & TRIGGER_MASK == TRIGGER_LEVEL,
+            }),
+            _ => None,
+        })
+    }
+}
+
+pub struct MadtEntries {
+    bytes: &'static [u8],
+}
+
+impl Iterator for MadtEntries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.len() < 2 {
+            return None;
+        }
+
+        let entry_type = self.bytes[0];
+        let length = self.bytes[1] as usize;
+        if length < 2 || length > self.bytes.len() {
+            return None;
+        }
+
+        let (e, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+
+        let entry = match (entry_type, length) {
+            (0, 8) => MadtEntry::LocalApic {
+                processor_id: e[2],
+                apic_id: e[3],
+                flags: read_u32(e, 4),
+            },
+            (1, 12) => MadtEntry::IoApic {
+                id: e[2],
+                address: read_u32(e, 4),
+                gsi_base: read_u32(e, 8),
+            },
+            (2, 10) => MadtEntry::InterruptOverride {
+                bus: e[2],
+                irq: e[3],
+                gsi: read_u32(e, 4),
+                flags: read_u16(e, 8),
+            },
+            (4, 6) => MadtEntry::LocalApicNmi {
+                processor_id: e[2],
+                flags: read_u16(e, 3),
+                lint: e[5],
+            },
+            (5, 12) => MadtEntry::LocalApicAddressOverride {
+                address: read_u64(e, 4),
+            },
+            (9, 16) => MadtEntry::LocalX2Apic {
+                x2apic_id: read_u32(e, 4),
+                flags: read_u32(e, 8),
+                processor_uid: read_u32(e, 12),
+            },
+            (entry_type, _) => MadtEntry::Unknown { entry_type },
+        };
+
+        Some(entry)
+    }
+}
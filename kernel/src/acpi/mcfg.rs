@@ -0,0 +1,47 @@
+use x86_64::PhysAddr;
+
+use super::{SdtHeader, find_table, read_u16, read_u64};
+
+const SIGNATURE: &[u8; 4] = b"MCFG";
+
+// Offsets from the start of the table, there are 8 reserved bytes after the header
+const ENTRIES: usize = 44;
+const ENTRY_SIZE: usize = 16;
+
+/// The PCIe enhanced configuration space (ECAM) of a range of buses in a segment group.
+#[derive(Debug, Clone, Copy)]
+pub struct EcamRegion {
+    pub base_address: PhysAddr,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// The PCI Express memory mapped configuration space table.
+#[derive(Debug, Clone, Copy)]
+pub struct Mcfg {
+    table: &'static SdtHeader,
+}
+
+impl Mcfg {
+    pub fn get() -> Option<Self> {
+        let table = find_table(SIGNATURE)?;
+
+        if table.as_bytes().len() < ENTRIES {
+            return None;
+        }
+
+        Some(Mcfg { table })
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = EcamRegion> {
+        self.table.as_bytes()[ENTRIES..]
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| EcamRegion {
+                base_address: PhysAddr::new(read_u64(entry, 0)),
+                segment_group: read_u16(entry, 8),
+                start_bus: entry[10],
+                end_bus: entry[11],
+            })
+    }
+}
@@ -0,0 +1,277 @@
+use alloc::{boxed::Box, vec::Vec};
+use core::{mem, slice};
+use x86_64::PhysAddr;
+
+use crate::{memory::phys_to_virt, sync::Locked};
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDT_SIGNATURE: &[u8; 4] = b"RSDT";
+const XSDT_SIGNATURE: &[u8; 4] = b"XSDT";
+pub const DSDT_SIGNATURE: &[u8; 4] = b"DSDT";
+
+/// The size of the ACPI 1.0 part of the RSDP, which is covered by the first checksum.
+const RSDP_V1_SIZE: usize = 20;
+
+#[derive(Debug)]
+pub enum AcpiError {
+    NoRsdp,
+    InvalidRsdpSignature,
+    InvalidRsdpChecksum,
+    InvalidRootSignature,
+    InvalidRootChecksum,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header every system description table starts with.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    pub fn signature(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    /// The whole table, including the header.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) }
+    }
+
+    /// Everything after the header.
+    pub fn data(&self) -> &[u8] {
+        &self.as_bytes()[mem::size_of::<SdtHeader>()..]
+    }
+
+    pub fn is_valid(&self) -> bool {
+        (self.length as usize) >= mem::size_of::<SdtHeader>() && checksum(self.as_bytes())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RootTable {
+    Rsdt(&'static SdtHeader),
+    Xsdt(&'static SdtHeader),
+}
+
+impl RootTable {
+    fn header(&self) -> &'static SdtHeader {
+        match *self {
+            RootTable::Rsdt(header) | RootTable::Xsdt(header) => header,
+        }
+    }
+
+    fn entry_size(&self) -> usize {
+        match self {
+            RootTable::Rsdt(_) => mem::size_of::<u32>(),
+            RootTable::Xsdt(_) => mem::size_of::<u64>(),
+        }
+    }
+}
+
+// Copies of every table, so firmware memory can be reclaimed.
+static TABLES: Locked<&'static [&'static SdtHeader]> = Locked::new(&[]);
+
+/// The ACPI Generic Address Structure, describing where a register lives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    const SIZE: usize = 12;
+
+    fn parse(bytes: &[u8], offset: usize) -> Option<Self> {
+        if bytes.len() < offset + Self::SIZE {
+            return None;
+        }
+
+        let address_space = match bytes[offset] {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+
+        Some(GenericAddress {
+            address_space,
+            bit_width: bytes[offset + 1],
+            bit_offset: bytes[offset + 2],
+            access_size: bytes[offset + 3],
+            address: read_u64(bytes, offset + 4),
+        })
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+unsafe fn table_at(address: u64) -> &'static SdtHeader {
+    &*phys_to_virt(PhysAddr::new(address)).as_ptr()
+}
+
+/// Iterator over the tables listed in the RSDT or XSDT, as found in firmware memory.
+struct RootEntries {
+    entries: &'static [u8],
+    entry_size: usize,
+}
+
+impl RootEntries {
+    fn new(root: RootTable) -> Self {
+        RootEntries {
+            entries: root.header().data(),
+            entry_size: root.entry_size(),
+        }
+    }
+}
+
+impl Iterator for RootEntries {
+    type Item = &'static SdtHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.entries.len() < self.entry_size {
+                return None;
+            }
+
+            let (entry, rest) = self.entries.split_at(self.entry_size);
+            self.entries = rest;
+
+            let address = match self.entry_size {
+                4 => read_u32(entry, 0) as u64,
+                _ => read_u64(entry, 0),
+            };
+
+            let table = unsafe { table_at(address) };
+            if table.is_valid() {
+                return Some(table);
+            }
+        }
+    }
+}
+
+/// Copy a table out of firmware memory. Tables are never freed.
+fn copy_table(table: &SdtHeader) -> &'static SdtHeader {
+    let copy: &'static mut [u8] = Box::leak(table.as_bytes().into());
+    unsafe { &*(copy.as_ptr() as *const SdtHeader) }
+}
+
+/// Every table with a valid checksum, including the DSDT, or none at all if ACPI is not available.
+pub fn tables() -> impl Iterator<Item = &'static SdtHeader> {
+    let tables: &'static [&'static SdtHeader] = *TABLES.lock();
+    tables.iter().copied()
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    tables().find(|table| &table.signature == signature)
+}
+
+pub fn init(rsdp_address: Option<u64>) -> Result<(), AcpiError> {
+    let rsdp_address = rsdp_address.ok_or(AcpiError::NoRsdp)?;
+    let rsdp_ptr = phys_to_virt(PhysAddr::new(rsdp_address)).as_ptr::<u8>();
+    let rsdp: Rsdp = unsafe { core::ptr::read_unaligned(rsdp_ptr as *const Rsdp) };
+
+    if &rsdp.signature != RSDP_SIGNATURE {
+        return Err(AcpiError::InvalidRsdpSignature);
+    }
+
+    let v1 = unsafe { slice::from_raw_parts(rsdp_ptr, RSDP_V1_SIZE) };
+    if !checksum(v1) {
+        return Err(AcpiError::InvalidRsdpChecksum);
+    }
+
+    // Prefer the XSDT whenever the extended part of the RSDP checks out.
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 && checksum(unsafe { slice::from_raw_parts(rsdp_ptr, rsdp.length as usize) }) {
+        let header = unsafe { table_at(rsdp.xsdt_address) };
+        if &header.signature != XSDT_SIGNATURE {
+            return Err(AcpiError::InvalidRootSignature);
+        }
+        RootTable::Xsdt(header)
+    } else {
+        let header = unsafe { table_at(rsdp.rsdt_address as u64) };
+        if &header.signature != RSDT_SIGNATURE {
+            return Err(AcpiError::InvalidRootSignature);
+        }
+        RootTable::Rsdt(header)
+    };
+
+    if !root.header().is_valid() {
+        return Err(AcpiError::InvalidRootChecksum);
+    }
+
+    let mut copies: Vec<&'static SdtHeader> = RootEntries::new(root).map(copy_table).collect();
+
+    // The DSDT is only referenced by the FADT, but it lives in reclaimable memory as well.
+    let dsdt = copies.iter()
+        .find_map(|&table| fadt::Fadt::from_table(table))
+        .map(|fadt| fadt.dsdt_address())
+        .filter(|&address| address != 0)
+        .map(|address| unsafe { table_at(address) })
+        .filter(|table| &table.signature == DSDT_SIGNATURE && table.is_valid());
+    if let Some(dsdt) = dsdt {
+        copies.push(copy_table(dsdt));
+    }
+    *TABLES.lock() = copies.leak();
+
+    crate::print!("ACPI: {} tables:", root.header().signature());
+    for table in tables() {
+        crate::print!(" {}", table.signature());
+    }
+    crate::println!();
+
+    Ok(())
+}
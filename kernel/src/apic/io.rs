@@ -0,0 +1,117 @@
+use x86_64::PhysAddr;
+
+use crate::memory::{CacheMode, mmio::{self, Mmio}};
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_BASE: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL_TRIGGERED: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+/// The default location of the first I/O APIC.
+pub const LEGACY_IO_APIC_ADDRESS: u64 = 0xFEC0_0000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    // Not needed to program it, but part of what the MADT tells us.
+    #[allow(dead_code)]
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// How an ISA IRQ is wired to the I/O APIC, if it differs from the identity mapping.
+#[derive(Debug, Clone, Copy)]
+pub struct IrqOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+impl IrqOverride {
+    /// ISA IRQs are edge triggered and active high, unless told otherwise.
+    pub fn identity(irq: u8) -> Self {
+        IrqOverride {
+            irq,
+            gsi: irq as u32,
+            active_low: false,
+            level_triggered: false,
+        }
+    }
+}
+
+pub struct IoApic {
+    base: Mmio,
+    gsi_base: u32,
+    num_entries: u32,
+}
+
+impl IoApic {
+    pub fn new(info: IoApicInfo) -> Self {
+        let base = mmio::map(info.address, 0x20, CacheMode::Uncached).expect("Could not map I/O APIC");
+
+        let mut io_apic = IoApic {
+            base,
+            gsi_base: info.gsi_base,
+            num_entries: 0,
+        };
+
+        io_apic.num_entries = unsafe { (io_apic.read(REG_VERSION) >> 16) & 0xFF } + 1;
+
+        io_apic
+    }
+
+    unsafe fn read(&self, reg: u32) -> u32 {
+        core::ptr::write_volatile((self.base.addr() + IOREGSEL).as_mut_ptr(), reg);
+        core::ptr::read_volatile((self.base.addr() + IOWIN).as_ptr())
+    }
+
+    unsafe fn write(&mut self, reg: u32, value: u32) {
+        core::ptr::write_volatile((self.base.addr() + IOREGSEL).as_mut_ptr(), reg);
+        core::ptr::write_volatile((self.base.addr() + IOWIN).as_mut_ptr(), value);
+    }
+
+    unsafe fn set_entry(&mut self, index: u32, entry: u64) {
+        let reg = REG_REDIRECTION_BASE + index * 2;
+        self.write(reg, entry as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.num_entries
+    }
+
+    pub fn mask_all(&mut self) {
+        for index in 0..self.num_entries {
+            unsafe {
+                self.set_entry(index, ENTRY_MASKED);
+            }
+        }
+    }
+
+    /// Deliver `route` as `vector` to the local APIC with id `apic_id`.
+    pub fn route(&mut self, route: IrqOverride, vector: u8, apic_id: u32) {
+        let mut entry = vector as u64 | ((apic_id as u64) << 56);
+        if route.active_low {
+            entry |= ENTRY_ACTIVE_LOW;
+        }
+        if route.level_triggered {
+            entry |= ENTRY_LEVEL_TRIGGERED;
+        }
+
+        unsafe {
+            self.set_entry(route.gsi - self.gsi_base, entry);
+        }
+    }
+
+    pub fn mask(&mut self, gsi: u32) {
+        unsafe {
+            self.set_entry(gsi - self.gsi_base, ENTRY_MASKED);
+        }
+    }
+}
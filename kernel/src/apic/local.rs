@@ -0,0 +1,167 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering, fence};
+use x86_64::{PhysAddr, registers::model_specific::Msr};
+
+use crate::{cpu::{self, Feature}, memory::{CacheMode, mmio}, sync::relax};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// Register offsets, as used in xAPIC mode.
+pub const REG_ID: u32 = 0x20;
+pub const REG_TPR: u32 = 0x80;
+pub const REG_EOI: u32 = 0xB0;
+pub const REG_SPURIOUS: u32 = 0xF0;
+pub const REG_ICR_LOW: u32 = 0x300;
+pub const REG_ICR_HIGH: u32 = 0x310;
+pub const REG_LVT_TIMER: u32 = 0x320;
+pub const REG_LVT_ERROR: u32 = 0x370;
+pub const REG_TIMER_INITIAL: u32 = 0x380;
+pub const REG_TIMER_CURRENT: u32 = 0x390;
+pub const REG_TIMER_DIVIDE: u32 = 0x3E0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+pub const ICR_INIT: u32 = 0b101 << 8 | 1 << 14;
+pub const ICR_STARTUP: u32 = 0b110 << 8 | 1 << 14;
+pub const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+static X2APIC: AtomicBool = AtomicBool::new(false);
+static XAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+pub fn is_x2apic() -> bool {
+    X2APIC.load(Ordering::Relaxed)
+}
+
+pub unsafe fn read(reg: u32) -> u32 {
+    if is_x2apic() {
+        Msr::new(X2APIC_MSR_BASE + (reg >> 4)).read() as u32
+    } else {
+        let addr = XAPIC_BASE.load(Ordering::Relaxed) + reg as u64;
+        core::ptr::read_volatile(addr as *const u32)
+    }
+}
+
+pub unsafe fn write(reg: u32, value: u32) {
+    if is_x2apic() {
+        Msr::new(X2APIC_MSR_BASE + (reg >> 4)).write(value as u64);
+    } else {
+        let addr = XAPIC_BASE.load(Ordering::Relaxed) + reg as u64;
+        core::ptr::write_volatile(addr as *mut u32, value);
+    }
+}
+
+pub fn id() -> u32 {
+    let id = unsafe { read(REG_ID) };
+
+    if is_x2apic() {
+        id
+    } else {
+        id >> 24
+    }
+}
+
+pub fn eoi() {
+    unsafe {
+        write(REG_EOI, 0);
+    }
+}
+
+/// Send an interrupt command to the local APIC with the given `apic_id`.
+pub unsafe fn send_ipi(apic_id: u32, command: u32) {
+    if is_x2apic() {
+        // In x2APIC mode the ICR is a single 64 bit register.
+        Msr::new(X2APIC_MSR_BASE + (REG_ICR_LOW >> 4)).write(((apic_id as u64) << 32) | command as u64);
+    } else {
+        write(REG_ICR_HIGH, apic_id << 24);
+        write(REG_ICR_LOW, command);
+
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            relax();
+        }
+    }
+}
+
+/// Count how many timer ticks pass while `wait` busy waits.
+pub fn calibrate_timer(wait: impl FnOnce()) -> u32 {
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(REG_LVT_TIMER, LVT_MASKED);
+        write(REG_TIMER_INITIAL, u32::MAX);
+
+        wait();
+
+        let elapsed = u32::MAX - read(REG_TIMER_CURRENT);
+        write(REG_TIMER_INITIAL, 0);
+        elapsed
+    }
+}
+
+/// Raise `vector` once, after `ticks` timer ticks as measured by `calibrate_timer`.
+pub fn start_oneshot_timer(vector: u8, ticks: u32) {
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(REG_LVT_TIMER, vector as u32);
+        // Zero would stop the timer instead.
+        write(REG_TIMER_INITIAL, ticks.max(1));
+    }
+}
+
+/// Raise `vector` once the TSC reaches `deadline`, right away if it already did.
+pub fn start_tsc_deadline_timer(vector: u8, deadline: u64) {
+    unsafe {
+        write(REG_LVT_TIMER, LVT_TIMER_TSC_DEADLINE | vector as u32);
+        // The MSR write isn't ordered with the LVT write, which might be to MMIO.
+        fence(Ordering::SeqCst);
+        // Zero would disarm the timer instead.
+        Msr::new(IA32_TSC_DEADLINE).write(deadline.max(1));
+    }
+}
+
+pub fn stop_timer() {
+    unsafe {
+        // Changing the mode disarms a TSC deadline.
+        write(REG_LVT_TIMER, LVT_MASKED);
+        write(REG_TIMER_INITIAL, 0);
+    }
+}
+
+/// Pick xAPIC or x2APIC mode and enable the local APIC of the bootstrap processor.
+pub fn init_bsp(spurious_vector: u8) {
+    let x2apic = cpu::has(Feature::X2Apic);
+    X2APIC.store(x2apic, Ordering::Relaxed);
+
+    if !x2apic {
+        let base = unsafe { Msr::new(IA32_APIC_BASE).read() } & APIC_BASE_ADDRESS_MASK;
+        let virt = mmio::map(PhysAddr::new(base), 0x1000, CacheMode::Uncached).expect("Could not map local APIC").leak();
+        XAPIC_BASE.store(virt.as_u64(), Ordering::Relaxed);
+    }
+
+    init(spurious_vector);
+}
+
+/// Enable the local APIC of the current processor, in the mode the bootstrap processor picked.
+pub fn init(spurious_vector: u8) {
+    unsafe {
+        // x2APIC mode can only be entered from an enabled xAPIC.
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let value = apic_base.read() | APIC_BASE_ENABLE;
+        apic_base.write(value);
+        if is_x2apic() {
+            apic_base.write(value | APIC_BASE_X2APIC);
+        }
+
+        write(REG_TPR, 0);
+        write(REG_LVT_TIMER, LVT_MASKED);
+        write(REG_LVT_ERROR, LVT_MASKED);
+        write(REG_SPURIOUS, SPURIOUS_ENABLE | spurious_vector as u32);
+    }
+}
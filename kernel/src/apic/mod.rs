@@ -0,0 +1,94 @@
+use crate::{interrupts::{PIC_VECTOR_BASE, SPURIOUS_VECTOR}, sync::Locked};
+
+use self::io::IoApic;
+
+pub use self::io::{IoApicInfo, IrqOverride, LEGACY_IO_APIC_ADDRESS};
+
+pub mod local;
+mod io;
+mod pic;
+
+const MAX_IO_APICS: usize = 8;
+const NUM_ISA_IRQS: usize = 16;
+
+struct IoApics {
+    apics: [Option<IoApic>; MAX_IO_APICS],
+    isa_routes: [Option<IrqOverride>; NUM_ISA_IRQS],
+}
+
+impl IoApics {
+    const fn new() -> Self {
+        const NO_APIC: Option<IoApic> = None;
+
+        IoApics {
+            apics: [NO_APIC; MAX_IO_APICS],
+            isa_routes: [None; NUM_ISA_IRQS],
+        }
+    }
+
+    fn isa_route(&self, irq: u8) -> IrqOverride {
+        self.isa_routes
+            .get(irq as usize)
+            .copied()
+            .flatten()
+            .unwrap_or_else(|| IrqOverride::identity(irq))
+    }
+
+    fn for_gsi(&mut self, gsi: u32) -> Option<&mut IoApic> {
+        self.apics.iter_mut().flatten().find(|apic| apic.handles(gsi))
+    }
+}
+
+static IO_APICS: Locked<IoApics> = Locked::new(IoApics::new());
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteError {
+    /// None of the I/O APICs has an input for this GSI.
+    NoIoApic(u32),
+}
+
+/// Replace the legacy PIC with the local APIC and the given I/O APICs.
+/// Every I/O APIC input starts out masked, until a handler is registered for it.
+pub fn init<A, O>(io_apics: A, overrides: O)
+where
+    A: IntoIterator<Item = IoApicInfo>,
+    O: IntoIterator<Item = IrqOverride>,
+{
+    pic::disable(PIC_VECTOR_BASE);
+    local::init_bsp(SPURIOUS_VECTOR);
+
+    let mut state = IO_APICS.lock();
+
+    for (slot, info) in state.apics.iter_mut().zip(io_apics) {
+        let mut io_apic = IoApic::new(info);
+        io_apic.mask_all();
+        *slot = Some(io_apic);
+    }
+
+    for route in overrides {
+        if let Some(slot) = state.isa_routes.get_mut(route.irq as usize) {
+            *slot = Some(route);
+        }
+    }
+}
+
+/// The global system interrupt an ISA IRQ is connected to.
+pub fn isa_irq_gsi(irq: u8) -> u32 {
+    IO_APICS.lock().isa_route(irq).gsi
+}
+
+/// Unmask the ISA IRQ `irq` and deliver it as `vector` to the current processor.
+pub fn route_isa_irq(irq: u8, vector: u8) -> Result<(), RouteError> {
+    let mut state = IO_APICS.lock();
+    let route = state.isa_route(irq);
+
+    let io_apic = state.for_gsi(route.gsi).ok_or(RouteError::NoIoApic(route.gsi))?;
+    io_apic.route(route, vector, local::id());
+    Ok(())
+}
+
+pub fn mask_gsi(gsi: u32) {
+    if let Some(io_apic) = IO_APICS.lock().for_gsi(gsi) {
+        io_apic.mask(gsi);
+    }
+}
@@ -0,0 +1,36 @@
+use x86_64::instructions::port::Port;
+
+const PIC1_COMMAND: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_COMMAND: u16 = 0xA0;
+const PIC2_DATA: u16 = 0xA1;
+
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+
+/// Remap the legacy PICs to `vector_base` and mask every line.
+/// They can still raise spurious interrupts, which is why they need to be remapped at all.
+pub fn disable(vector_base: u8) {
+    let mut pic1_command: Port<u8> = Port::new(PIC1_COMMAND);
+    let mut pic1_data: Port<u8> = Port::new(PIC1_DATA);
+    let mut pic2_command: Port<u8> = Port::new(PIC2_COMMAND);
+    let mut pic2_data: Port<u8> = Port::new(PIC2_DATA);
+
+    unsafe {
+        pic1_command.write(ICW1_INIT);
+        pic2_command.write(ICW1_INIT);
+
+        pic1_data.write(vector_base);
+        pic2_data.write(vector_base + 8);
+
+        // PIC2 is cascaded through IRQ2 of PIC1
+        pic1_data.write(1 << 2);
+        pic2_data.write(2);
+
+        pic1_data.write(ICW4_8086);
+        pic2_data.write(ICW4_8086);
+
+        pic1_data.write(0xFF);
+        pic2_data.write(0xFF);
+    }
+}
@@ -1,9 +1,11 @@
 
+use alloc::vec::Vec;
 use core::{fmt};
-use bootinfo::boot_info::{ConsoleFont, FrameBuffer};
+use bootinfo::boot_info::{ConsoleFont, FrameBuffer, FrameBufferInfo};
 
 const BYTES_PER_PIXEL: usize = 4;
 
+#[derive(Clone, Copy)]
 struct Color {
     r: u8,
     g: u8,
@@ -28,10 +30,7 @@ impl Color {
     }
 }
 
-fn draw_pixel(mut frame_buffer: FrameBuffer, x: usize, y: usize, color: Color) {
-    let info = frame_buffer.info();
-    let buffer = frame_buffer.buffer_mut();
-
+fn draw_pixel(buffer: &mut [u8], info: FrameBufferInfo, x: usize, y: usize, color: Color) {
     if x >= info.width || y >= info.height {
         return;
     }
@@ -44,9 +43,21 @@ fn draw_pixel(mut frame_buffer: FrameBuffer, x: usize, y: usize, color: Color) {
     buffer[index+2] = color.r;
 }
 
+/// Draw to the framebuffer and its copy, if there is one.
+fn put_pixel(frame_buffer: &mut FrameBuffer, shadow: &mut Option<Vec<u8>>, x: usize, y: usize, color: Color) {
+    let info = frame_buffer.info();
+
+    draw_pixel(frame_buffer.buffer_mut(), info, x, y, color);
+    if let Some(shadow) = shadow.as_mut() {
+        draw_pixel(shadow, info, x, y, color);
+    }
+}
+
 pub struct FrameBufferWriter {
     frame_buffer: FrameBuffer,
     font: psf::Font<ConsoleFont>,
+    // A copy of the framebuffer in normal memory, so scrolling doesn't have to read it back.
+    shadow: Option<Vec<u8>>,
     x: usize,
     y: usize,
 }
@@ -56,17 +67,27 @@ impl FrameBufferWriter {
         FrameBufferWriter {
             frame_buffer,
             font: psf::Font::new(font).unwrap(),
+            shadow: None,
             x: 0,
             y: 0,
         }
     }
 
+    /// Keep a copy of the framebuffer from now on. This needs the heap.
+    pub fn enable_shadow(&mut self) {
+        self.shadow = Some(self.frame_buffer.buffer().to_vec());
+    }
+
+    pub fn framebuffer(&self) -> &FrameBuffer {
+        &self.frame_buffer
+    }
+
     pub fn clear(&mut self) {
         let info = self.frame_buffer.info();
 
         for y in 0..info.height {
             for x in 0..info.width {
-                draw_pixel(self.frame_buffer, x, y, Color::black());
+                put_pixel(&mut self.frame_buffer, &mut self.shadow, x, y, Color::black());
             }
         }
 
@@ -81,7 +102,7 @@ impl FrameBufferWriter {
                 for x in 0..glyph.width() {
                     let pixel = glyph.pixel(x, y).unwrap_or(false);
                     let pixel = if pixel { Color::white() } else { Color::black() };
-                    draw_pixel(self.frame_buffer, self.x + x as usize, self.y + y as usize, pixel);
+                    put_pixel(&mut self.frame_buffer, &mut self.shadow, self.x + x as usize, self.y + y as usize, pixel);
                 }
             }
         }
@@ -89,29 +110,27 @@ impl FrameBufferWriter {
 
     fn scroll_line(&mut self) {
         let info = self.frame_buffer.info();
-        let buffer = self.frame_buffer.buffer_mut();
         let font_height = self.font.height() as usize;
 
-        // Scroll screen
-        {
-            let count = info.stride * (info.height - font_height) * BYTES_PER_PIXEL;
-            let dst = buffer.as_mut_ptr();
-            let src = buffer.as_ptr().wrapping_offset((info.stride*font_height*BYTES_PER_PIXEL) as _);
-            unsafe {
-                core::ptr::copy(src, dst, count);
-            }
-        }
+        let line = info.stride * font_height * BYTES_PER_PIXEL;
+        let total = info.stride * info.height * BYTES_PER_PIXEL;
 
-        // Clear now scrolled section
-        {
-            unsafe {
-                let offset = info.stride * (info.height - font_height) * BYTES_PER_PIXEL;
-                let total = info.stride * info.height * BYTES_PER_PIXEL;
-                let dst = buffer.as_mut_ptr().wrapping_offset(offset as _);
-                let count = total - offset;
-
-                core::ptr::write_bytes(dst, 0, count)
-            }
+        match self.shadow.as_mut() {
+            // Scroll the copy, and only ever write to the framebuffer.
+            Some(shadow) => {
+                shadow.copy_within(line..total, 0);
+                for byte in &mut shadow[total - line..total] {
+                    *byte = 0;
+                }
+                self.frame_buffer.buffer_mut()[..total].copy_from_slice(&shadow[..total]);
+            },
+            None => {
+                let buffer = self.frame_buffer.buffer_mut();
+                buffer.copy_within(line..total, 0);
+                for byte in &mut buffer[total - line..total] {
+                    *byte = 0;
+                }
+            },
         }
     }
 
@@ -1,7 +1,9 @@
 use bootinfo::boot_info::{ConsoleFont, FrameBuffer};
-use spinning_top::{Spinlock, const_spinlock};
+use x86_64::{VirtAddr, structures::paging::{Page, PageTableFlags}};
 use uart_16550::SerialPort;
-use core::{fmt, panic::PanicInfo};
+use core::{fmt, panic::PanicInfo, sync::atomic::{AtomicBool, Ordering}};
+
+use crate::{memory::{CacheMode, mapper}, sync::Locked, time::{self, DateTime}};
 
 use self::framebuffer::FrameBufferWriter;
 
@@ -9,8 +11,12 @@ mod framebuffer;
 
 const COM1: u16 = 0x3F8;
 
-static FRAMEBUFFER_WRITER: Spinlock<Option<FrameBufferWriter>> = const_spinlock(None);
-static SERIAL_WRITER: Spinlock<SerialPort> = const_spinlock(unsafe { SerialPort::new(COM1) });
+static FRAMEBUFFER_WRITER: Locked<Option<FrameBufferWriter>> = Locked::new(None);
+static SERIAL_WRITER: Locked<SerialPort> = Locked::new(unsafe { SerialPort::new(COM1) });
+
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+// Whether the next thing printed starts a new line, and so gets a timestamp.
+static LINE_START: AtomicBool = AtomicBool::new(true);
 
 pub fn init(frame_buffer: FrameBuffer, font: ConsoleFont) {
     // Initialize FrameBufferWriter
@@ -25,17 +31,84 @@ pub fn init(frame_buffer: FrameBuffer, font: ConsoleFont) {
     SERIAL_WRITER.lock().init();
 }
 
+/// Map the framebuffer write-combining, now that the kernel has its own page tables and heap.
+pub fn init_late() {
+    let mut fb_writer = FRAMEBUFFER_WRITER.lock();
+    let fb_writer = match fb_writer.as_mut() {
+        Some(fb_writer) => fb_writer,
+        None => return,
+    };
+
+    // Reading write-combining memory is slow, so keep a copy to scroll.
+    fb_writer.enable_shadow();
+
+    let buffer = fb_writer.framebuffer().buffer();
+    let start: Page = Page::containing_address(VirtAddr::from_ptr(buffer.as_ptr()));
+    let end: Page = Page::containing_address(VirtAddr::from_ptr(buffer.as_ptr()) + (buffer.len() - 1));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | CacheMode::WriteCombining.flags();
+
+    unsafe {
+        mapper::protect(Page::range(start, end + 1), flags).expect("Framebuffer is not mapped");
+    }
+}
+
+/// Start every line with the wall-clock time, once `time` knows it.
+pub fn set_timestamps(enabled: bool) {
+    TIMESTAMPS.store(enabled, Ordering::Relaxed);
+}
+
+/// Writes to the framebuffer and the serial port, with `stamp` in front of every line.
+struct Console<'a> {
+    framebuffer: Option<&'a mut FrameBufferWriter>,
+    serial: &'a mut SerialPort,
+    stamp: Option<DateTime>,
+}
+
+impl Console<'_> {
+    fn output(&mut self, args: fmt::Arguments) -> fmt::Result {
+        use fmt::Write;
+
+        if let Some(framebuffer) = self.framebuffer.as_mut() {
+            framebuffer.write_fmt(args)?;
+        }
+        self.serial.write_fmt(args)
+    }
+}
+
+impl fmt::Write for Console<'_> {
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        while !s.is_empty() {
+            if let Some(stamp) = self.stamp {
+                if LINE_START.load(Ordering::Relaxed) {
+                    self.output(format_args!("[{}] ", stamp))?;
+                }
+            }
+
+            let end = s.find('\n').map_or(s.len(), |newline| newline + 1);
+            let (line, rest) = s.split_at(end);
+            self.output(format_args!("{}", line))?;
+            LINE_START.store(line.ends_with('\n'), Ordering::Relaxed);
+            s = rest;
+        }
+
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use fmt::Write;
 
-    //TODO Probably disable interrupts whilst locking; to prevent deadlocks
-    
-    if let Some(writer) = FRAMEBUFFER_WRITER.lock().as_mut() {
-        writer.write_fmt(args).unwrap()
-    }
+    let stamp = if TIMESTAMPS.load(Ordering::Relaxed) { Some(time::wall_clock()) } else { None };
 
-    SERIAL_WRITER.lock().write_fmt(args).unwrap();
+    let mut framebuffer = FRAMEBUFFER_WRITER.lock();
+    let mut serial = SERIAL_WRITER.lock();
+    let mut console = Console {
+        framebuffer: framebuffer.as_mut(),
+        serial: &mut serial,
+        stamp,
+    };
+    console.write_fmt(args).unwrap();
 }
 
 #[macro_export]
@@ -45,20 +118,29 @@ macro_rules! print {
 
 #[macro_export]
 macro_rules! println {
-    () => (print!("\n"));
+    () => ($crate::print!("\n"));
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Force unlock the console; in case it was locked.
+/// This is only OK if whoever held the lock is never going to run again.
+pub unsafe fn force_unlock() {
+    FRAMEBUFFER_WRITER.force_unlock();
+    SERIAL_WRITER.force_unlock();
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    // Force unlock the mutex; in case it was locked.
     // This is OK since we're panicking.
     unsafe {
-        FRAMEBUFFER_WRITER.force_unlock();
-        SERIAL_WRITER.force_unlock();
+        force_unlock();
     }
 
     println!("{}", info);
-    
-    loop { core::hint::spin_loop(); }
+
+    x86_64::instructions::interrupts::disable();
+    loop {
+        // Only an NMI gets us out of this.
+        x86_64::instructions::hlt();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,137 @@
+//! What the CPU supports, according to CPUID.
+//! Detected once on the bootstrap processor, the others are assumed to be the same.
+
+use core::{arch::x86_64::{CpuidResult, __cpuid, __cpuid_count}, sync::atomic::{AtomicU32, Ordering}};
+
+use crate::println;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feature {
+    X2Apic,
+    Pcid,
+    Invpcid,
+    Xsave,
+    /// 1GiB pages.
+    GibPages,
+    Rdrand,
+    TscDeadline,
+    InvariantTsc,
+    Smep,
+    Smap,
+    Umip,
+    /// MONITOR and MWAIT.
+    Monitor,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 12] = [
+        Feature::X2Apic,
+        Feature::Pcid,
+        Feature::Invpcid,
+        Feature::Xsave,
+        Feature::GibPages,
+        Feature::Rdrand,
+        Feature::TscDeadline,
+        Feature::InvariantTsc,
+        Feature::Smep,
+        Feature::Smap,
+        Feature::Umip,
+        Feature::Monitor,
+    ];
+
+    /// The CPUID leaf, register and bit that report this feature.
+    fn location(self) -> (u32, Register, u32) {
+        match self {
+            Feature::Monitor => (0x1, Register::Ecx, 3),
+            Feature::X2Apic => (0x1, Register::Ecx, 21),
+            Feature::Pcid => (0x1, Register::Ecx, 17),
+            Feature::Xsave => (0x1, Register::Ecx, 26),
+            Feature::Rdrand => (0x1, Register::Ecx, 30),
+            Feature::TscDeadline => (0x1, Register::Ecx, 24),
+            Feature::Smep => (0x7, Register::Ebx, 7),
+            Feature::Invpcid => (0x7, Register::Ebx, 10),
+            Feature::Smap => (0x7, Register::Ebx, 20),
+            Feature::Umip => (0x7, Register::Ecx, 2),
+            Feature::GibPages => (0x8000_0001, Register::Edx, 26),
+            Feature::InvariantTsc => (0x8000_0007, Register::Edx, 8),
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+// A bit per `Feature`, only written by `init`.
+static FEATURES: AtomicU32 = AtomicU32::new(0);
+
+/// CPUID `leaf`, or nothing if the CPU doesn't have it.
+fn cpuid(leaf: u32) -> Option<CpuidResult> {
+    // Basic and extended leaves each have their own maximum.
+    let max = unsafe { __cpuid(leaf & 0x8000_0000) }.eax;
+    if leaf > max {
+        return None;
+    }
+
+    Some(unsafe { __cpuid_count(leaf, 0) })
+}
+
+pub fn has(feature: Feature) -> bool {
+    FEATURES.load(Ordering::Relaxed) & feature.bit() != 0
+}
+
+fn brand(buffer: &mut [u8; 48]) -> Option<&str> {
+    for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+        let result = cpuid(leaf)?;
+        for (j, register) in [result.eax, result.ebx, result.ecx, result.edx].iter().enumerate() {
+            buffer[i * 16 + j * 4..][..4].copy_from_slice(&register.to_le_bytes());
+        }
+    }
+
+    core::str::from_utf8(buffer).ok().map(|brand| brand.trim_matches(|c: char| c == '\0' || c == ' '))
+}
+
+/// Watch the cache line of `addr` for writes, for the next `enable_and_mwait`.
+pub unsafe fn monitor(addr: *const u8) {
+    asm!("monitor", in("rax") addr, in("ecx") 0, in("edx") 0, options(nostack, readonly, preserves_flags));
+}
+
+/// Enable interrupts and wait for one, or for a write to the monitored line.
+/// Like `sti; hlt`, an interrupt can't slip in between the two.
+pub unsafe fn enable_and_mwait() {
+    asm!("sti; mwait", in("eax") 0, in("ecx") 0, options(nostack));
+}
+
+/// Detect the features of the bootstrap processor and log them.
+pub fn init() {
+    let mut features = 0;
+    for &feature in Feature::ALL.iter() {
+        let (leaf, register, bit) = feature.location();
+        let value = match (cpuid(leaf), register) {
+            (Some(result), Register::Ebx) => result.ebx,
+            (Some(result), Register::Ecx) => result.ecx,
+            (Some(result), Register::Edx) => result.edx,
+            (None, _) => 0,
+        };
+
+        if value & (1 << bit) != 0 {
+            features |= feature.bit();
+        }
+    }
+    FEATURES.store(features, Ordering::Relaxed);
+
+    let mut buffer = [0; 48];
+    println!("CPU: {}", brand(&mut buffer).unwrap_or("unknown"));
+    crate::print!("CPU features:");
+    for &feature in Feature::ALL.iter().filter(|&&feature| has(feature)) {
+        crate::print!(" {:?}", feature);
+    }
+    println!();
+}
@@ -0,0 +1,101 @@
+use core::cell::UnsafeCell;
+use x86_64::{PrivilegeLevel, VirtAddr, instructions::{segmentation, tables::load_tss}, structures::{gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector}, tss::TaskStateSegment}};
+
+use crate::{memory::stack::KernelStack, smp::MAX_CPUS};
+
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+const NUM_IST_STACKS: usize = 3;
+const IST_STACK_SIZE: usize = 0x1000 * 4;
+
+// The CPU pushes onto it, so it has to be writable and can't end up in .rodata.
+#[repr(align(16))]
+struct IstStack(UnsafeCell<[u8; IST_STACK_SIZE]>);
+
+// Only the CPU touches the contents, we just hand out the address.
+unsafe impl Sync for IstStack {}
+
+impl IstStack {
+    const fn new() -> Self {
+        IstStack(UnsafeCell::new([0; IST_STACK_SIZE]))
+    }
+
+    fn top(&'static self) -> VirtAddr {
+        VirtAddr::from_ptr(self.0.get()) + IST_STACK_SIZE
+    }
+}
+
+// The bootstrap processor sets up its tables before there is a frame allocator,
+// so its interrupt stacks are static.
+static BSP_IST_STACKS: [IstStack; NUM_IST_STACKS] = [IstStack::new(), IstStack::new(), IstStack::new()];
+
+// Every CPU gets its own TSS, and thus its own GDT.
+// These are only ever written by `init_cpu`, before they are handed to the CPU.
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [TaskStateSegment::new(); MAX_CPUS];
+const EMPTY_GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+static mut GDT: [GlobalDescriptorTable; MAX_CPUS] = [EMPTY_GDT; MAX_CPUS];
+
+unsafe fn init_cpu(cpu: usize, ist_stacks: [VirtAddr; NUM_IST_STACKS]) {
+    let tss = &mut TSS[cpu];
+    let gdt = &mut GDT[cpu];
+
+    // The TSS is packed, so the table can't be borrowed in place.
+    let mut ist = tss.interrupt_stack_table;
+    ist[..NUM_IST_STACKS].copy_from_slice(&ist_stacks);
+    tss.interrupt_stack_table = ist;
+
+    // The order of these needs to match the selectors above.
+    // SYSRET expects the user data segment to come right before the user code segment.
+    assert_eq!(gdt.add_entry(Descriptor::kernel_code_segment()), KERNEL_CODE_SELECTOR);
+    assert_eq!(gdt.add_entry(Descriptor::kernel_data_segment()), KERNEL_DATA_SELECTOR);
+    assert_eq!(gdt.add_entry(Descriptor::user_data_segment()), USER_DATA_SELECTOR);
+    assert_eq!(gdt.add_entry(Descriptor::user_code_segment()), USER_CODE_SELECTOR);
+    assert_eq!(gdt.add_entry(Descriptor::tss_segment(&TSS[cpu])), TSS_SELECTOR);
+
+    gdt.load_unsafe();
+
+    segmentation::set_cs(KERNEL_CODE_SELECTOR);
+    segmentation::load_ss(KERNEL_DATA_SELECTOR);
+    segmentation::load_ds(KERNEL_DATA_SELECTOR);
+    segmentation::load_es(KERNEL_DATA_SELECTOR);
+    segmentation::load_fs(SegmentSelector(0));
+    segmentation::load_gs(SegmentSelector(0));
+
+    load_tss(TSS_SELECTOR);
+}
+
+/// Load the GDT and TSS of the bootstrap processor.
+pub fn init() {
+    let mut ist_stacks = [VirtAddr::zero(); NUM_IST_STACKS];
+    for (top, stack) in ist_stacks.iter_mut().zip(BSP_IST_STACKS.iter()) {
+        *top = stack.top();
+    }
+
+    unsafe {
+        init_cpu(0, ist_stacks);
+    }
+}
+
+/// Load the GDT and TSS of an application processor, giving it guarded interrupt stacks.
+pub fn init_ap(cpu: usize) {
+    let mut ist_stacks = [VirtAddr::zero(); NUM_IST_STACKS];
+    for top in ist_stacks.iter_mut() {
+        let stack = KernelStack::new().expect("Could not allocate interrupt stack");
+        *top = stack.top();
+
+        // Interrupt stacks live as long as the CPU does, which is forever.
+        core::mem::forget(stack);
+    }
+
+    unsafe {
+        init_cpu(cpu, ist_stacks);
+    }
+}
@@ -0,0 +1,86 @@
+// Every vector gets a 16 byte aligned stub, so the stub for vector `n` lives at
+// `interrupt_stubs + n * 16`. Each stub makes sure an error code is on the stack,
+// pushes its vector number and jumps to `interrupt_common`, which saves all
+// general purpose registers and hands an `InterruptFrame` to `interrupt_dispatch`.
+global_asm!(r#"
+    .section .text.interrupts, "ax"
+    .global interrupt_stubs
+    .p2align 4
+interrupt_stubs:
+    .set interrupt_vector, 0
+    .rept 256
+    .p2align 4
+    .if !(interrupt_vector == 8 || (interrupt_vector >= 10 && interrupt_vector <= 14) || interrupt_vector == 17 || interrupt_vector == 21 || interrupt_vector == 29 || interrupt_vector == 30)
+    pushq $0
+    .endif
+    pushq $interrupt_vector
+    jmp interrupt_common
+    .set interrupt_vector, interrupt_vector + 1
+    .endr
+
+interrupt_common:
+    // Coming from user mode, swap in the kernel GS base.
+    testb $3, 24(%rsp)
+    jz 1f
+    swapgs
+1:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+
+    // Don't let the handler inherit user memory access from a `stac` it interrupted.
+    pushfq
+    andq $~(1 << 18), (%rsp)
+    popfq
+
+    movq %rsp, %rdi
+    cld
+    call interrupt_dispatch
+
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+
+    // Skip vector and error code
+    addq $16, %rsp
+
+    testb $3, 8(%rsp)
+    jz 2f
+    swapgs
+2:
+    iretq
+"#, options(att_syntax));
+
+pub const STUB_SIZE: u64 = 16;
+
+extern "C" {
+    static interrupt_stubs: u8;
+}
+
+pub fn stub_address(vector: usize) -> u64 {
+    unsafe { &interrupt_stubs as *const u8 as u64 + vector as u64 * STUB_SIZE }
+}
@@ -0,0 +1,133 @@
+use core::fmt;
+use x86_64::{VirtAddr, instructions::{hlt, interrupts}, registers::control::Cr2, structures::idt::PageFaultErrorCode};
+
+use crate::{memory::stack::{self, GuardHit}, println, user};
+
+use super::InterruptFrame;
+
+const DOUBLE_FAULT: u64 = 8;
+const PAGE_FAULT: u64 = 14;
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "Divide Error (#DE)",
+    "Debug (#DB)",
+    "Non-Maskable Interrupt (NMI)",
+    "Breakpoint (#BP)",
+    "Overflow (#OF)",
+    "Bound Range Exceeded (#BR)",
+    "Invalid Opcode (#UD)",
+    "Device Not Available (#NM)",
+    "Double Fault (#DF)",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS (#TS)",
+    "Segment Not Present (#NP)",
+    "Stack-Segment Fault (#SS)",
+    "General Protection Fault (#GP)",
+    "Page Fault (#PF)",
+    "Reserved",
+    "x87 Floating-Point Exception (#MF)",
+    "Alignment Check (#AC)",
+    "Machine Check (#MC)",
+    "SIMD Floating-Point Exception (#XM)",
+    "Virtualization Exception (#VE)",
+    "Control Protection Exception (#CP)",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception (#HV)",
+    "VMM Communication Exception (#VC)",
+    "Security Exception (#SX)",
+    "Reserved",
+];
+
+impl fmt::Display for InterruptFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "RIP={:016X} CS={:04X} RFLAGS={:016X}", self.rip, self.cs, self.rflags)?;
+        writeln!(f, "RSP={:016X} SS={:04X}", self.rsp, self.ss)?;
+        writeln!(f, "RAX={:016X} RBX={:016X} RCX={:016X}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "RDX={:016X} RSI={:016X} RDI={:016X}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "RBP={:016X} R8 ={:016X} R9 ={:016X}", self.rbp, self.r8, self.r9)?;
+        writeln!(f, "R10={:016X} R11={:016X} R12={:016X}", self.r10, self.r11, self.r12)?;
+        write!(f, "R13={:016X} R14={:016X} R15={:016X}", self.r13, self.r14, self.r15)
+    }
+}
+
+pub fn handle(frame: &mut InterruptFrame) {
+    match frame.vector {
+        DOUBLE_FAULT => double_fault(frame),
+        PAGE_FAULT => page_fault(frame),
+        _ => fatal(frame),
+    }
+}
+
+fn page_fault(frame: &mut InterruptFrame) {
+    let addr = Cr2::read();
+
+    if let Some(resume) = user::fixup(frame.rip, addr) {
+        frame.rip = resume;
+        return;
+    }
+
+    if let Some(hit) = stack::guard_hit(addr) {
+        stack_overflow(frame, hit, addr);
+    }
+
+    fatal(frame);
+}
+
+/// An overflow that moved the stack pointer into the guard page can't have its page fault
+/// pushed, so it shows up here with CR2 still pointing into the guard page.
+fn double_fault(frame: &mut InterruptFrame) {
+    let addr = Cr2::read();
+
+    if let Some(hit) = stack::guard_hit(addr).or_else(|| stack::guard_hit(VirtAddr::new_truncate(frame.rsp))) {
+        stack_overflow(frame, hit, addr);
+    }
+
+    fatal(frame);
+}
+
+fn stack_overflow(frame: &InterruptFrame, hit: GuardHit, addr: VirtAddr) -> ! {
+    stop();
+
+    match hit {
+        GuardHit::BootStack => println!("EXCEPTION: kernel stack overflow (boot stack)"),
+        GuardHit::Vmalloc(start) => println!("EXCEPTION: kernel stack overflow (guard page below {:#X})", start.as_u64()),
+    }
+    println!("RIP={:#X} CR2={:#X}", frame.rip, addr.as_u64());
+    println!("{}", frame);
+
+    halt();
+}
+
+/// Make sure we can print, no matter what state the console was in.
+fn stop() {
+    interrupts::disable();
+
+    // We are not coming back from this, so whoever was holding the console can't anymore.
+    unsafe {
+        crate::console::force_unlock();
+    }
+}
+
+fn halt() -> ! {
+    loop { hlt(); }
+}
+
+fn fatal(frame: &InterruptFrame) -> ! {
+    stop();
+
+    println!("EXCEPTION: {}", EXCEPTION_NAMES[frame.vector as usize]);
+    println!("Vector={} Error code={:#X} CR2={:#X}", frame.vector, frame.error_code, Cr2::read().as_u64());
+
+    if frame.vector == PAGE_FAULT {
+        println!("{:?}", PageFaultErrorCode::from_bits_truncate(frame.error_code));
+    }
+
+    println!("{}", frame);
+
+    halt();
+}
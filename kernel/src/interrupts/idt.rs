@@ -0,0 +1,73 @@
+use x86_64::{VirtAddr, instructions::{segmentation, tables::lidt}, structures::DescriptorTablePointer};
+
+const IDT_ENTRIES: usize = 256;
+
+// Present, DPL 0, 64-bit interrupt gate
+const INTERRUPT_GATE: u8 = 0x8E;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    type_attr: u8,
+    offset_middle: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+impl IdtEntry {
+    pub const fn missing() -> Self {
+        IdtEntry {
+            offset_low: 0,
+            selector: 0,
+            ist: 0,
+            type_attr: 0,
+            offset_middle: 0,
+            offset_high: 0,
+            reserved: 0,
+        }
+    }
+
+    pub fn set_handler(&mut self, handler: u64) {
+        self.offset_low = handler as u16;
+        self.offset_middle = (handler >> 16) as u16;
+        self.offset_high = (handler >> 32) as u32;
+        self.selector = segmentation::cs().0;
+        self.type_attr = INTERRUPT_GATE;
+    }
+
+    /// Switch to the given interrupt stack from the TSS when this entry is invoked.
+    pub fn set_stack_index(&mut self, index: u16) {
+        // An IST of 0 means no stack switch, so the hardware index is off by one.
+        self.ist = (index + 1) as u8;
+    }
+}
+
+#[repr(C, align(16))]
+pub struct Idt {
+    entries: [IdtEntry; IDT_ENTRIES],
+}
+
+impl Idt {
+    pub const fn new() -> Self {
+        Idt {
+            entries: [IdtEntry::missing(); IDT_ENTRIES],
+        }
+    }
+
+    pub fn entry_mut(&mut self, vector: usize) -> &mut IdtEntry {
+        &mut self.entries[vector]
+    }
+
+    /// The IDT has to stay at the same address for as long as it is loaded.
+    pub unsafe fn load(&self) {
+        let pointer = DescriptorTablePointer {
+            base: VirtAddr::new(self as *const _ as u64),
+            limit: (core::mem::size_of::<Self>() - 1) as u16,
+        };
+
+        lidt(&pointer);
+    }
+}
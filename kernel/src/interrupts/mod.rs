@@ -0,0 +1,166 @@
+use core::cell::Cell;
+use x86_64::instructions::interrupts;
+
+use crate::{apic::{self, RouteError}, gdt, percpu, sync::{Locked, RwLock}, thread};
+
+use self::idt::Idt;
+
+mod entry;
+mod exceptions;
+mod idt;
+
+const NUM_VECTORS: usize = 256;
+const NUM_EXCEPTIONS: usize = 32;
+
+/// Device interrupts are delivered at `IRQ_VECTOR_BASE + gsi`.
+pub const IRQ_VECTOR_BASE: u8 = 0x20;
+/// The masked legacy PICs are remapped here, so their spurious interrupts can be ignored.
+pub const PIC_VECTOR_BASE: u8 = 0xE0;
+pub const TIMER_VECTOR: u8 = 0xF0;
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF1;
+pub const RESCHEDULE_VECTOR: u8 = 0xF2;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+pub type Handler = fn(&mut InterruptFrame);
+
+const NMI_VECTOR: usize = 2;
+const DOUBLE_FAULT_VECTOR: usize = 8;
+const MACHINE_CHECK_VECTOR: usize = 18;
+
+static IDT: Locked<Idt> = Locked::new(Idt::new());
+// Read on every interrupt, on every CPU, but hardly ever written.
+static HANDLERS: RwLock<[Option<Handler>; NUM_VECTORS]> = RwLock::new([None; NUM_VECTORS]);
+
+percpu! {
+    static IN_INTERRUPT: Cell<bool> = Cell::new(false);
+}
+
+/// The state of the interrupted code, as saved by `interrupt_common`.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    pub vector: u64,
+    pub error_code: u64,
+
+    // Pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+#[no_mangle]
+extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) {
+    match frame.vector as usize {
+        vector if vector < NUM_EXCEPTIONS => exceptions::handle(frame),
+        vector if vector == SPURIOUS_VECTOR as usize => {},
+        vector if (PIC_VECTOR_BASE as usize..PIC_VECTOR_BASE as usize + 16).contains(&vector) => {},
+        vector => {
+            let handler = HANDLERS.read()[vector];
+
+            // Acknowledge before handling, we might switch to another thread before returning.
+            // Interrupts stay disabled until we return, so this can't nest.
+            apic::local::eoi();
+
+            IN_INTERRUPT.with(|in_interrupt| in_interrupt.set(true));
+
+            match handler {
+                Some(handler) => handler(frame),
+                None => crate::println!("Unhandled interrupt {}", vector),
+            }
+
+            IN_INTERRUPT.with(|in_interrupt| in_interrupt.set(false));
+
+            thread::preempt();
+        },
+    }
+}
+
+/// Whether the current CPU is running an interrupt handler.
+pub fn in_interrupt() -> bool {
+    IN_INTERRUPT.with(|in_interrupt| in_interrupt.get())
+}
+
+/// Call `handler` whenever `vector` is raised.
+pub fn register_handler(vector: u8, handler: Handler) {
+    assert!(vector as usize >= NUM_EXCEPTIONS, "Vector {} is reserved for exceptions", vector);
+
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        assert!(handlers[vector as usize].is_none(), "Vector {} already has a handler", vector);
+        handlers[vector as usize] = Some(handler);
+    });
+}
+
+pub fn unregister_handler(vector: u8) {
+    interrupts::without_interrupts(|| {
+        HANDLERS.write()[vector as usize] = None;
+    });
+}
+
+fn irq_vector(gsi: u32) -> u8 {
+    let vector = IRQ_VECTOR_BASE as u32 + gsi;
+    assert!(vector < PIC_VECTOR_BASE as u32, "GSI {} is out of range", gsi);
+    vector as u8
+}
+
+/// Call `handler` whenever the ISA IRQ `irq` fires.
+pub fn register_irq(irq: u8, handler: Handler) -> Result<(), RouteError> {
+    let vector = irq_vector(apic::isa_irq_gsi(irq));
+
+    register_handler(vector, handler);
+    apic::route_isa_irq(irq, vector).map_err(|err| {
+        unregister_handler(vector);
+        err
+    })
+}
+
+pub fn unregister_irq(irq: u8) {
+    let gsi = apic::isa_irq_gsi(irq);
+
+    apic::mask_gsi(gsi);
+    unregister_handler(irq_vector(gsi));
+}
+
+pub fn init() {
+    let mut idt = IDT.lock();
+
+    for vector in 0..NUM_VECTORS {
+        idt.entry_mut(vector).set_handler(entry::stub_address(vector));
+    }
+
+    // These can happen when the current stack is unusable, so they get their own.
+    // A kernel stack overflow can't push a page fault onto the guard page, so it ends up as a double fault.
+    // Page faults themselves stay on the current stack, so they can nest.
+    idt.entry_mut(NMI_VECTOR).set_stack_index(gdt::NMI_IST_INDEX);
+    idt.entry_mut(DOUBLE_FAULT_VECTOR).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    idt.entry_mut(MACHINE_CHECK_VECTOR).set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+
+    unsafe {
+        idt.load();
+    }
+}
+
+/// Load the shared IDT on an application processor.
+pub fn init_ap() {
+    unsafe {
+        IDT.lock().load();
+    }
+}
@@ -1,13 +1,27 @@
 #![no_std]
 #![no_main]
 #![feature(asm)]
+#![feature(global_asm)]
 #![feature(alloc_error_handler)]
 #![feature(const_mut_refs)] // For fixed_size_block
 
 extern crate alloc;
 
+mod acpi;
+mod apic;
 mod console;
+mod cpu;
+mod gdt;
+mod interrupts;
 mod memory;
+mod percpu;
+mod selftest;
+mod smbios;
+mod smp;
+mod sync;
+mod thread;
+mod time;
+mod user;
 
 use bootinfo::boot_info::BootInfo;
 
@@ -21,11 +35,73 @@ use bootinfo::boot_info::BootInfo;
 #[no_mangle]
 pub extern "C" fn _start(boot_info: &'static mut BootInfo) -> ! {
     console::init(boot_info.frame_buffer, boot_info.console_font);
-    memory::init(&boot_info.memory_map);
+    cpu::init();
+    gdt::init();
+    interrupts::init();
+    memory::init(&boot_info.memory_map, &boot_info.frame_buffer);
+    console::init_late();
+    user::init();
+
+    if let Err(err) = acpi::init(boot_info.rsdp_address) {
+        println!("ACPI not available: {:?}", err);
+    }
+
+    if let Some(fadt) = acpi::fadt::Fadt::get() {
+        let bits = if fadt.pm_timer_is_32bit() { 32 } else { 24 };
+        println!("FADT: SCI on GSI {}, reset register {}", fadt.sci_interrupt(), if fadt.reset_register().is_some() { "present" } else { "missing" });
+        if let Some(timer) = fadt.pm_timer_block() {
+            println!("FADT: {}-bit PM timer at {:?} {:#X}", bits, timer.address.address_space, timer.address.address);
+        }
+    }
+
+    if let Some(mcfg) = acpi::mcfg::Mcfg::get() {
+        for region in mcfg.regions() {
+            println!("MCFG: PCIe segment {} buses {}-{} at {:#X}", region.segment_group, region.start_bus, region.end_bus, region.base_address.as_u64());
+        }
+    }
+
+    match smbios::init(boot_info.smbios_address) {
+        Ok(()) => {
+            if let Some((vendor, product)) = smbios::system() {
+                println!("SMBIOS: {} {}", vendor, product);
+            }
+        }
+        Err(err) => println!("SMBIOS not available: {:?}", err),
+    }
+
+    // ACPI has copied its tables by now, so firmware memory can go.
+    memory::reclaim(&boot_info.memory_map);
+    println!("{}", memory::stats());
+
+    time::init();
+
+    match acpi::madt::Madt::get() {
+        Some(madt) => {
+            println!("MADT: {} processors, {} I/O APICs", madt.processors().count(), madt.io_apics().count());
+            apic::init(madt.io_apics(), madt.interrupt_overrides());
+        },
+        None => {
+            // Without a MADT, assume a single I/O APIC in its usual place.
+            apic::init(
+                core::iter::once(apic::IoApicInfo {
+                    id: 0,
+                    address: x86_64::PhysAddr::new(apic::LEGACY_IO_APIC_ADDRESS),
+                    gsi_base: 0,
+                }),
+                core::iter::empty(),
+            );
+        },
+    }
+    smp::init();
+    thread::init();
+    x86_64::instructions::interrupts::enable();
+
+    smp::start_aps();
+    selftest::run();
 
     let x = alloc::boxed::Box::new(5);
 
     println!("Hello, World! {}", x);
 
-    loop {}
+    thread::exit();
 }
\ No newline at end of file
@@ -0,0 +1,150 @@
+use core::{alloc::{GlobalAlloc, Layout}, mem, ptr::{NonNull, null_mut}};
+
+use crate::sync::Locked;
+
+use super::{MapError, PAGE_SIZE, align_up, linked_list::{LinkedListAllocator, NODE_SIZE}, map_pages};
+
+/// The size classes for small allocations. Each block is aligned to its size,
+/// so these need to be powers of two.
+const BLOCK_SIZES: &[usize] = &[16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Grow the heap by at least this much at a time.
+const GROW_SIZE: usize = 64 * 1024;
+
+/// Heap usage in bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Handed out and not freed yet.
+    pub used: usize,
+    /// The most that was ever in use at once.
+    pub peak: usize,
+    /// Backed by frames.
+    pub mapped: usize,
+}
+
+struct Block {
+    next: Option<&'static mut Block>,
+}
+
+/// Small allocations come from a free list of blocks per size class,
+/// anything bigger goes straight to the linked list allocator.
+/// When that runs out, more of the heap area gets mapped.
+pub struct FixedSizeBlockAllocator {
+    heads: [Option<&'static mut Block>; BLOCK_SIZES.len()],
+    fallback: LinkedListAllocator,
+    heap_start: usize,
+    heap_end: usize,
+    heap_top: usize,
+    used: usize,
+    peak: usize,
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut Block> = None;
+        FixedSizeBlockAllocator {
+            heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback: LinkedListAllocator::new(),
+            heap_start: 0,
+            heap_end: 0,
+            heap_top: 0,
+            used: 0,
+            peak: 0,
+        }
+    }
+
+    /// Use the unmapped area from `heap_start` to `heap_top` as heap.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_top: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start;
+        self.heap_top = heap_top;
+    }
+
+    /// Map at least `size` more bytes at the end of the heap.
+    fn grow(&mut self, size: usize) -> Result<(), MapError> {
+        let size = align_up(size.max(GROW_SIZE), PAGE_SIZE).min(self.heap_top - self.heap_end);
+        if size == 0 {
+            return Err(MapError::NoVirtualSpace);
+        }
+
+        unsafe {
+            let mapped = map_pages(self.heap_end, size)?;
+            self.fallback.add_free_region(self.heap_end, mapped);
+            self.heap_end += mapped;
+        }
+
+        Ok(())
+    }
+
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        loop {
+            let ptr = self.fallback.allocate(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+
+            // Enough for the allocation even if the new memory can't merge with the last free region.
+            if self.grow(layout.size() + layout.align() + NODE_SIZE).is_err() {
+                return null_mut();
+            }
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            used: self.used,
+            peak: self.peak,
+            mapped: self.heap_end - self.heap_start,
+        }
+    }
+
+    fn list_index(layout: &Layout) -> Option<usize> {
+        let required = layout.size().max(layout.align());
+        BLOCK_SIZES.iter().position(|&size| size >= required)
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+
+        let ptr = match FixedSizeBlockAllocator::list_index(&layout) {
+            Some(index) => match allocator.heads[index].take() {
+                Some(block) => {
+                    allocator.heads[index] = block.next.take();
+                    block as *mut Block as *mut u8
+                },
+                None => {
+                    // Blocks are carved out of the fallback one at a time, and never go back.
+                    let size = BLOCK_SIZES[index];
+                    allocator.fallback_alloc(Layout::from_size_align_unchecked(size, size))
+                },
+            },
+            None => allocator.fallback_alloc(layout),
+        };
+
+        if !ptr.is_null() {
+            allocator.used += layout.size();
+            allocator.peak = allocator.peak.max(allocator.used);
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.used -= layout.size();
+
+        match FixedSizeBlockAllocator::list_index(&layout) {
+            Some(index) => {
+                assert!(mem::size_of::<Block>() <= BLOCK_SIZES[index]);
+                let mut block = NonNull::new_unchecked(ptr as *mut Block);
+                block.as_ptr().write(Block {
+                    next: allocator.heads[index].take(),
+                });
+                allocator.heads[index] = Some(block.as_mut());
+            },
+            None => allocator.fallback.deallocate(ptr, layout),
+        }
+    }
+}
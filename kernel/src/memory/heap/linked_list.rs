@@ -0,0 +1,141 @@
+use core::{alloc::Layout, mem, ptr::null_mut};
+
+use super::align_up;
+
+/// Every free region starts with a `ListNode`, so nothing smaller than this is handed out.
+pub const NODE_SIZE: usize = mem::size_of::<ListNode>();
+
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode {
+            size,
+            next: None,
+        }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// A first fit allocator over a free list that is kept sorted by address,
+/// so freed regions merge with their neighbours.
+pub struct LinkedListAllocator {
+    head: ListNode,
+}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head: ListNode::new(0),
+        }
+    }
+
+    /// Hand the region to the allocator. It has to be unused and `NODE_SIZE` aligned.
+    pub unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        assert_eq!(align_up(addr, NODE_SIZE), addr);
+        assert!(size >= NODE_SIZE && size % NODE_SIZE == 0);
+
+        // Find the last region that starts before this one.
+        let head: *mut ListNode = &mut self.head;
+        let mut previous = head;
+        while let Some(next) = (*previous).next.as_mut() {
+            if next.start_addr() > addr {
+                break;
+            }
+            previous = &mut **next;
+        }
+
+        let node = addr as *mut ListNode;
+        node.write(ListNode {
+            size,
+            next: (*previous).next.take(),
+        });
+
+        if let Some(next) = (*node).next.take() {
+            if (*node).end_addr() == next.start_addr() {
+                (*node).size += next.size;
+                (*node).next = next.next.take();
+            } else {
+                (*node).next = Some(next);
+            }
+        }
+
+        if previous != head && (*previous).end_addr() == addr {
+            (*previous).size += (*node).size;
+            (*previous).next = (*node).next.take();
+        } else {
+            (*previous).next = Some(&mut *node);
+        }
+    }
+
+    /// Where an allocation would start in `region`, if it fits.
+    /// Whatever is left over on either side has to be big enough to be a region itself.
+    fn fit(region: &ListNode, size: usize, align: usize) -> Option<usize> {
+        let mut start = align_up(region.start_addr(), align);
+        if start != region.start_addr() && start - region.start_addr() < NODE_SIZE {
+            start = align_up(region.start_addr() + NODE_SIZE, align);
+        }
+
+        let end = start.checked_add(size)?;
+        if end > region.end_addr() {
+            return None;
+        }
+
+        let excess = region.end_addr() - end;
+        if excess > 0 && excess < NODE_SIZE {
+            return None;
+        }
+
+        Some(start)
+    }
+
+    /// The size and alignment that is actually used for `layout`.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let size = align_up(layout.size().max(NODE_SIZE), NODE_SIZE);
+        let align = layout.align().max(NODE_SIZE);
+        (size, align)
+    }
+
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+
+        unsafe {
+            let mut previous: *mut ListNode = &mut self.head;
+            while let Some(region) = (*previous).next.as_mut() {
+                if let Some(start) = Self::fit(region, size, align) {
+                    let region_start = region.start_addr();
+                    let region_end = region.end_addr();
+                    (*previous).next = region.next.take();
+
+                    if start > region_start {
+                        self.add_free_region(region_start, start - region_start);
+                    }
+                    if start + size < region_end {
+                        self.add_free_region(start + size, region_end - start - size);
+                    }
+
+                    return start as *mut u8;
+                }
+
+                previous = &mut **region;
+            }
+        }
+
+        null_mut()
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+}
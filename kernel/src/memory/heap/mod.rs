@@ -1,27 +1,66 @@
-use bootinfo::memory_layout::{HEAP_BASE};
+use bootinfo::memory_layout::{HEAP_BASE, HEAP_TOP};
 use x86_64::{VirtAddr, structures::paging::{Page, PageTableFlags}};
 
-use self::bump::BumpAllocator;
+use self::fixed_size_block::FixedSizeBlockAllocator;
 
-use super::{Locked, phys::PhysAlloc};
+pub use self::fixed_size_block::HeapStats;
 
-mod bump;
+use crate::sync::Locked;
+
+use super::{mapper::MapError, phys::PhysAlloc};
+
+mod fixed_size_block;
+mod linked_list;
+
+const PAGE_SIZE: usize = 0x1000;
 
 #[global_allocator]
-static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("Allocation error {:?}", layout)
 }
 
+/// Align `addr` upwards to `align`, which must be a power of two.
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Back `size` bytes of heap at `start` with fresh frames.
+/// Returns how much could be mapped, only failing if that is nothing at all.
+unsafe fn map_pages(start: usize, size: usize) -> Result<usize, MapError> {
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start: Page = Page::containing_address(VirtAddr::new(start as u64));
+
+    let mut mapped = 0;
+    for page in Page::range(start, start + (size / PAGE_SIZE) as u64) {
+        let result = match PhysAlloc.allocate_frame() {
+            Some(frame) => super::mapper::map_page(page, frame, flags).map_err(|e| {
+                PhysAlloc.deallocate_frame(frame);
+                e
+            }),
+            None => Err(MapError::OutOfMemory),
+        };
+        match result {
+            Ok(()) => mapped += PAGE_SIZE,
+            Err(e) if mapped == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+
+    Ok(mapped)
+}
+
+pub fn stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+/// The heap starts out empty, and is mapped in as it grows.
 pub fn init() {
-    use x86_64::structures::paging::FrameAllocator;
     unsafe {
-        let page = Page::containing_address(VirtAddr::new(HEAP_BASE));
-        let frame = PhysAlloc{}.allocate_frame().unwrap();
-        
-        super::mapper::kernel_map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE);
-        ALLOCATOR.lock().init(HEAP_BASE, 0x1000);
+        ALLOCATOR.lock().init(HEAP_BASE as usize, HEAP_TOP as usize);
     }
-}
\ No newline at end of file
+}
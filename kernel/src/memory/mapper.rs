@@ -1,11 +1,53 @@
-use bootinfo::memory_layout::PHYSMAP_BASE;
-use spinning_top::{Spinlock, const_spinlock};
-use x86_64::{VirtAddr, registers::control::Cr3, structures::paging::{OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame}};
+use bootinfo::{boot_info::{FrameBuffer, MemoryMap, MemoryType}, memory_layout::{BOOTINFO_BASE, BOOTINFO_TOP, HEAP_BASE, HEAP_TOP, PHYSMAP_BASE, PHYSMAP_SIZE, STACK_GUARD, STACK_TOP}};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::{PhysAddr, VirtAddr, registers::control::Cr3, structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate, mapper::{FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError}, page::PageRange}};
 
-use crate::memory::phys::PhysAlloc;
+use crate::{cpu::{self, Feature}, memory::{phys::{self, PhysAlloc, Zone}, phys_to_virt, tlb}, sync::{TicketLock, TicketLockGuard}};
 
 static VIRT_PHYSMAP_OFFSET: VirtAddr = VirtAddr::new_truncate(PHYSMAP_BASE);
-static KERNEL_PAGE_TABLE: Spinlock<Option<OffsetPageTable>> = const_spinlock(None);
+// Every CPU maps and unmaps through here, so it is handed out in order.
+static KERNEL_PAGE_TABLE: TicketLock<Option<OffsetPageTable>> = TicketLock::new(None);
+static PAGE_TABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" {
+    // From linker.ld, all page aligned.
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+/// Regions the bootloader mapped for us that we keep, with the same frames.
+const CARRIED_OVER: [(u64, u64); 3] = [
+    (STACK_GUARD, STACK_TOP),
+    (BOOTINFO_BASE, BOOTINFO_TOP),
+    (HEAP_BASE, HEAP_TOP),
+];
+
+/// Hands out frames for page tables, and keeps count of them.
+struct PageTableAlloc;
+
+unsafe impl FrameAllocator<Size4KiB> for PageTableAlloc {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame = PhysAlloc.allocate_frame()?;
+        PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for PageTableAlloc {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+        PhysAlloc.deallocate_frame(frame);
+    }
+}
+
+/// How many frames the kernel allocated for page tables, the bootloader's aren't counted.
+pub fn page_table_frames() -> usize {
+    PAGE_TABLE_FRAMES.load(Ordering::Relaxed)
+}
 
 unsafe fn active_l4() -> &'static mut PageTable {
     let (l4, _) = Cr3::read();
@@ -17,18 +59,459 @@ unsafe fn active_l4() -> &'static mut PageTable {
     &mut *page_table_ptr
 }
 
-pub unsafe fn kernel_map_to(page: Page, frame: PhysFrame, flags: PageTableFlags) {
-    if let Some(mapper) = KERNEL_PAGE_TABLE.lock().as_mut() {
-        use x86_64::structures::paging::mapper::Mapper;
-        mapper.map_to(page, frame, flags, &mut PhysAlloc).unwrap().flush();
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapError {
+    NotInitialized,
+    OutOfMemory,
+    NoVirtualSpace,
+    AlreadyMapped(Page),
+    NotMapped(Page),
+    /// The page is part of a bigger page.
+    HugePage(Page),
+    InvalidFrame(Page),
+}
+
+// In a 4KiB page table entry the bit that marks huge pages elsewhere picks the upper half of the PAT.
+// The x86_64 crate doesn't know that, so it only ever gets set through `update_flags`.
+const PAT_4KIB: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+/// How the CPU may cache a mapping, using the slots `pat::init` sets up.
+/// Only for 4KiB pages, huge pages keep their PAT bit somewhere else.
+/// Write-back is what a mapping gets without any of these.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheMode {
+    WriteCombining,
+    Uncached,
+}
+
+impl CacheMode {
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteCombining => PAT_4KIB,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// A page as it is mapped right now.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub frame: PhysAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+}
+
+fn kernel_page_table() -> TicketLockGuard<'static, Option<OffsetPageTable<'static>>> {
+    KERNEL_PAGE_TABLE.lock()
+}
+
+fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> Result<R, MapError>) -> Result<R, MapError> {
+    f(kernel_page_table().as_mut().ok_or(MapError::NotInitialized)?)
+}
+
+unsafe fn map_one(mapper: &mut OffsetPageTable, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapError> {
+    match mapper.map_to(page, frame, flags - PAT_4KIB, &mut PageTableAlloc) {
+        // The page wasn't mapped, so no TLB can have it.
+        Ok(flush) => flush.ignore(),
+        Err(MapToError::FrameAllocationFailed) => return Err(MapError::OutOfMemory),
+        Err(MapToError::ParentEntryHugePage) => return Err(MapError::HugePage(page)),
+        Err(MapToError::PageAlreadyMapped(_)) => return Err(MapError::AlreadyMapped(page)),
     }
+
+    if flags.contains(PAT_4KIB) {
+        // Nothing used the page yet, but the CPU could have looked it up speculatively.
+        mapper.update_flags(page, flags).expect("Page was just mapped").flush();
+    }
+
+    Ok(())
 }
 
-pub fn init() {
+/// Unhook the page table that maps `page` if it is empty now, and the one above it if that
+/// ends up empty too. The level 3 and 4 tables are kept, there are only a few of them.
+unsafe fn unhook_empty_tables(mapper: &mut OffsetPageTable, page: Page) -> [Option<PhysFrame>; 2] {
+    let addr = page.start_address();
+    let l4 = mapper.level_4_table();
+    let l3: &mut PageTable = &mut *phys_to_virt(l4[addr.p4_index()].addr()).as_mut_ptr();
+    let l2: &mut PageTable = &mut *phys_to_virt(l3[addr.p3_index()].addr()).as_mut_ptr();
+    let l1 = table(l2[addr.p2_index()].addr());
+
+    let mut freed = [None, None];
+    if l1.iter().all(|entry| entry.is_unused()) {
+        freed[0] = Some(PhysFrame::containing_address(l2[addr.p2_index()].addr()));
+        l2[addr.p2_index()].set_unused();
+
+        if l2.iter().all(|entry| entry.is_unused()) {
+            freed[1] = Some(PhysFrame::containing_address(l3[addr.p3_index()].addr()));
+            l3[addr.p3_index()].set_unused();
+        }
+    }
+
+    freed
+}
+
+/// Unmap `pages`, stopping at the first one that isn't mapped. Frames are only freed once
+/// no TLB can have them anymore, so they get flushed in batches.
+/// Page tables left empty are freed as well.
+unsafe fn unmap_pages(mapper: &mut OffsetPageTable, pages: PageRange, free: bool) -> Result<(), MapError> {
+    let mut frames = [PhysFrame::containing_address(PhysAddr::zero()); tlb::FLUSH_ALL_PAGES];
+    let mut count = 0;
+    let mut batch = pages.start;
+
+    let finish = |batch: Page, frames: &[PhysFrame]| {
+        tlb::flush(batch.start_address(), (batch + frames.len() as u64).start_address());
+        if free {
+            for &frame in frames {
+                PhysAlloc.deallocate_frame(frame);
+            }
+        }
+    };
+
+    let mut result = Ok(());
+    for page in pages {
+        if let TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), flags, .. } = mapper.translate(page.start_address()) {
+            if flags.contains(PAT_4KIB) {
+                // Otherwise `unmap` takes it for a huge page. The flush below covers this too.
+                mapper.update_flags(page, flags - PAT_4KIB).expect("Page is mapped").ignore();
+            }
+        }
+
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.ignore();
+                frames[count] = frame;
+                count += 1;
+            },
+            Err(UnmapError::PageNotMapped) => result = Err(MapError::NotMapped(page)),
+            Err(UnmapError::ParentEntryHugePage) => result = Err(MapError::HugePage(page)),
+            Err(UnmapError::InvalidFrameAddress(_)) => result = Err(MapError::InvalidFrame(page)),
+        }
+
+        if result.is_err() {
+            break;
+        }
+
+        // Done with this page table, either because it's the last page or the next is in another one.
+        if page + 1 == pages.end || u16::from((page + 1).start_address().p1_index()) == 0 {
+            let tables = unhook_empty_tables(mapper, page);
+            if tables.iter().any(Option::is_some) {
+                // The flush also drops any cached walks through the tables, so they can go.
+                finish(batch, &frames[..count]);
+                batch = page + 1;
+                count = 0;
+                for &table in tables.iter().flatten() {
+                    PageTableAlloc.deallocate_frame(table);
+                }
+                continue;
+            }
+        }
+
+        if count == frames.len() {
+            finish(batch, &frames);
+            batch = page + 1;
+            count = 0;
+        }
+    }
+
+    finish(batch, &frames[..count]);
+    result
+}
+
+/// Map `pages` to consecutive frames starting at `frame`. Nothing is mapped on failure.
+pub unsafe fn map_range(pages: PageRange, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapError> {
+    with_mapper(|mapper| {
+        for (i, page) in pages.enumerate() {
+            if let Err(err) = map_one(mapper, page, frame + i as u64, flags) {
+                unmap_pages(mapper, Page::range(pages.start, page), false)?;
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    })
+}
+
+pub unsafe fn map_page(page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), MapError> {
+    map_range(Page::range(page, page + 1), frame, flags)
+}
+
+/// Back `pages` with newly allocated frames. Nothing is mapped on failure.
+pub unsafe fn map_allocated(pages: PageRange, flags: PageTableFlags) -> Result<(), MapError> {
+    with_mapper(|mapper| {
+        for page in pages {
+            let result = match PhysAlloc.allocate_frame() {
+                Some(frame) => map_one(mapper, page, frame, flags).map_err(|err| {
+                    PhysAlloc.deallocate_frame(frame);
+                    err
+                }),
+                None => Err(MapError::OutOfMemory),
+            };
+
+            if let Err(err) = result {
+                unmap_pages(mapper, Page::range(pages.start, page), true)?;
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Unmap `pages`, leaving the frames alone. Stops at the first page that isn't mapped.
+pub unsafe fn unmap_range(pages: PageRange) -> Result<(), MapError> {
+    with_mapper(|mapper| unmap_pages(mapper, pages, false))
+}
+
+pub unsafe fn unmap_page(page: Page) -> Result<(), MapError> {
+    unmap_range(Page::range(page, page + 1))
+}
+
+/// Unmap `pages` and give their frames back to the frame allocator.
+pub unsafe fn unmap_and_free(pages: PageRange) -> Result<(), MapError> {
+    with_mapper(|mapper| unmap_pages(mapper, pages, true))
+}
+
+/// Change the flags of every page in `pages`. Stops at the first page that isn't mapped.
+pub unsafe fn protect(pages: PageRange, flags: PageTableFlags) -> Result<(), MapError> {
+    with_mapper(|mapper| {
+        let mut result = Ok(());
+        let mut end = pages.start;
+        for page in pages {
+            match mapper.update_flags(page, flags) {
+                Ok(flush) => flush.ignore(),
+                Err(FlagUpdateError::PageNotMapped) => result = Err(MapError::NotMapped(page)),
+                Err(FlagUpdateError::ParentEntryHugePage) => result = Err(MapError::HugePage(page)),
+            }
+
+            if result.is_err() {
+                break;
+            }
+            end = page + 1;
+        }
+
+        tlb::flush(pages.start.start_address(), end.start_address());
+        result
+    })
+}
+
+/// How `addr` is mapped, if it is.
+pub fn query(addr: VirtAddr) -> Option<Mapping> {
+    match kernel_page_table().as_ref()?.translate(addr) {
+        TranslateResult::Mapped { frame, flags, .. } => Some(Mapping {
+            frame: frame.start_address(),
+            size: frame.size(),
+            flags,
+        }),
+        TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+    }
+}
+
+/// The physical address `addr` is mapped to, if it is.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    let mapping = query(addr)?;
+    Some(mapping.frame + (addr.as_u64() & (mapping.size - 1)))
+}
+
+/// Release a page table from the bootloader and every table below it, but not the pages they map.
+/// These were never counted in `PAGE_TABLE_FRAMES`, so that is left alone.
+unsafe fn free_table(table: PhysAddr, level: usize) -> usize {
+    let mut freed = 0;
+
+    if level > 1 {
+        let entries: &PageTable = &*phys_to_virt(table).as_ptr();
+        for entry in entries.iter() {
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE) {
+                freed += free_table(entry.addr(), level - 1);
+            }
+        }
+    }
+
+    // Page tables from the bootloader were never managed by the frame allocator.
+    freed + phys::release(table, table + Size4KiB::SIZE)
+}
+
+fn section(start: &'static u8, end: &'static u8) -> (VirtAddr, VirtAddr) {
+    (VirtAddr::from_ptr(start), VirtAddr::from_ptr(end))
+}
+
+unsafe fn table(entry_addr: PhysAddr) -> &'static PageTable {
+    &*phys_to_virt(entry_addr).as_ptr()
+}
+
+/// Map the kernel image section by section, to the frames the bootloader loaded it into.
+unsafe fn map_kernel(old: &OffsetPageTable, new: &mut OffsetPageTable) {
+    let sections = [
+        (section(&__text_start, &__text_end), PageTableFlags::PRESENT),
+        (section(&__rodata_start, &__rodata_end), PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE),
+        (section(&__data_start, &__data_end), PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE),
+    ];
+
+    for &((start, end), flags) in sections.iter() {
+        let start: Page = Page::containing_address(start);
+        let end: Page = Page::containing_address(end);
+        for page in Page::range(start, end) {
+            let frame = old.translate_page(page).expect("Kernel image is not mapped");
+            new.map_to(page, frame, flags, &mut PageTableAlloc).expect("Could not map kernel image").ignore();
+        }
+    }
+}
+
+/// Map `start..end` into the physmap, with the biggest pages that fit in it.
+/// 1GiB pages are only used for RAM, elsewhere they could easily reach into device memory.
+unsafe fn map_physmap_range(new: &mut OffsetPageTable, start: u64, end: u64, ram: bool) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let fits = |phys: u64, size: u64| phys % size == 0 && phys + size <= end;
+
+    let mut phys = start;
+    while phys < end {
+        let virt = VirtAddr::new(PHYSMAP_BASE + phys);
+        if ram && cpu::has(Feature::GibPages) && fits(phys, Size1GiB::SIZE) {
+            let page = Page::<Size1GiB>::containing_address(virt);
+            let frame = PhysFrame::<Size1GiB>::containing_address(PhysAddr::new(phys));
+            new.map_to(page, frame, flags, &mut PageTableAlloc).expect("Could not map physmap").ignore();
+            phys += Size1GiB::SIZE;
+        } else if fits(phys, Size2MiB::SIZE) {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(phys));
+            new.map_to(page, frame, flags, &mut PageTableAlloc).expect("Could not map physmap").ignore();
+            phys += Size2MiB::SIZE;
+        } else {
+            let page = Page::<Size4KiB>::containing_address(virt);
+            let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(phys));
+            new.map_to(page, frame, flags, &mut PageTableAlloc).expect("Could not map physmap").ignore();
+            phys += Size4KiB::SIZE;
+        }
+    }
+}
+
+/// Whether there is memory behind `memory_type`, rather than something the firmware keeps to itself.
+fn is_ram(memory_type: MemoryType) -> bool {
+    !matches!(memory_type, MemoryType::Unusable | MemoryType::Reserved | MemoryType::Mmio | MemoryType::MmioPortSpace | MemoryType::PalCode)
+}
+
+fn is_mmio(memory_type: MemoryType) -> bool {
+    matches!(memory_type, MemoryType::Mmio | MemoryType::MmioPortSpace)
+}
+
+/// Map everything in `map` at PHYSMAP_BASE, with the biggest pages the CPU has, except for MMIO
+/// and the holes in between. Devices get mapped uncached with `mmio::map` instead.
+/// `hole` is left out as well, so it can be mapped with another cache mode elsewhere without aliasing.
+unsafe fn map_physmap(map: &MemoryMap, hole: Option<(u64, u64)>, new: &mut OffsetPageTable) {
+    let entries = map.entries();
+
+    let mut index = 0;
+    while index < entries.len() {
+        let entry = &entries[index];
+        index += 1;
+        if is_mmio(entry.memory_type) {
+            continue;
+        }
+
+        // Neighbouring entries of the same kind can share bigger pages.
+        let ram = is_ram(entry.memory_type);
+        let start = entry.start;
+        let mut end = entry.start + entry.size as u64;
+        while let Some(next) = entries.get(index) {
+            if next.start != end || is_mmio(next.memory_type) || is_ram(next.memory_type) != ram {
+                break;
+            }
+            end += next.size as u64;
+            index += 1;
+        }
+        assert!(end <= PHYSMAP_SIZE, "Physical memory doesn't fit in the physmap");
+
+        match hole {
+            Some((hole_start, hole_end)) if hole_start < end && start < hole_end => {
+                map_physmap_range(new, start, hole_start.max(start), ram);
+                map_physmap_range(new, hole_end.min(end), end, ram);
+            },
+            _ => map_physmap_range(new, start, end, ram),
+        }
+    }
+}
+
+/// Copy whatever `old` maps in `start..end` into `new`, as data that is never executable.
+unsafe fn carry_over(old: &PageTable, new: &mut OffsetPageTable, start: u64, end: u64) {
+    // The address right after the naturally aligned block of `size` that `addr` is in.
+    fn next(addr: u64, size: u64) -> u64 {
+        (addr & !(size - 1)) + size
+    }
+
+    let keep = PageTableFlags::WRITABLE | PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE;
+    let extra = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+
+    let mut addr = start;
+    while addr < end {
+        let virt = VirtAddr::new(addr);
+
+        let l4_entry = &old[virt.p4_index()];
+        if !l4_entry.flags().contains(PageTableFlags::PRESENT) {
+            addr = next(addr, 512 * 1024 * 1024 * 1024);
+            continue;
+        }
+
+        let l3_entry = &table(l4_entry.addr())[virt.p3_index()];
+        if !l3_entry.flags().contains(PageTableFlags::PRESENT) {
+            addr = next(addr, 1024 * 1024 * 1024);
+            continue;
+        }
+
+        let l2_entry = &table(l3_entry.addr())[virt.p2_index()];
+        if !l2_entry.flags().contains(PageTableFlags::PRESENT) {
+            addr = next(addr, Size2MiB::SIZE);
+            continue;
+        }
+
+        if l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::containing_address(l2_entry.addr());
+            let flags = (l2_entry.flags() & keep) | extra;
+            new.map_to(page, frame, flags, &mut PageTableAlloc).expect("Could not carry over mapping").ignore();
+            addr = next(addr, Size2MiB::SIZE);
+            continue;
+        }
+
+        let l1_entry = &table(l2_entry.addr())[virt.p1_index()];
+        if l1_entry.flags().contains(PageTableFlags::PRESENT) {
+            let page = Page::<Size4KiB>::containing_address(virt);
+            let frame = PhysFrame::containing_address(l1_entry.addr());
+            let flags = (l1_entry.flags() & keep) | extra;
+            new.map_to(page, frame, flags, &mut PageTableAlloc).expect("Could not carry over mapping").ignore();
+        }
+        addr = next(addr, Size4KiB::SIZE);
+    }
+}
+
+/// Build the kernel's own address space and switch to it, dropping the one from the bootloader.
+/// Nothing in it is user accessible, and nothing is both writable and executable.
+pub fn init(map: &MemoryMap, framebuffer: &FrameBuffer) {
     unsafe {
-        let l4 = active_l4();
-        KERNEL_PAGE_TABLE
-            .lock()
-            .insert(OffsetPageTable::new(l4, VIRT_PHYSMAP_OFFSET));
+        let mut old = OffsetPageTable::new(active_l4(), VIRT_PHYSMAP_OFFSET);
+
+        // The console maps the framebuffer write-combining through the boot info, so it can't
+        // be in the physmap as write-back as well.
+        let framebuffer = old.translate_addr(VirtAddr::new(framebuffer.buffer_base)).map(|start| {
+            let end = start + framebuffer.buffer_size;
+            (start.align_down(Size4KiB::SIZE).as_u64(), end.align_up(Size4KiB::SIZE).as_u64())
+        });
+
+        // Application processors load this from 32-bit code in the trampoline, so it has to be below 4GiB.
+        let l4_frame = PhysAlloc.allocate_contiguous_in(0, Zone::Dma32).expect("Could not allocate page table");
+        PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
+        let l4: &'static mut PageTable = &mut *phys_to_virt(l4_frame.start_address()).as_mut_ptr();
+        l4.zero();
+        let mut new = OffsetPageTable::new(l4, VIRT_PHYSMAP_OFFSET);
+
+        map_kernel(&old, &mut new);
+        map_physmap(map, framebuffer, &mut new);
+        for &(start, end) in CARRIED_OVER.iter() {
+            carry_over(old.level_4_table(), &mut new, start, end);
+        }
+
+        let (old_l4, cr3_flags) = Cr3::read();
+        Cr3::write(l4_frame, cr3_flags);
+        *KERNEL_PAGE_TABLE.lock() = Some(new);
+
+        free_table(old_l4.start_address(), 4);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,36 @@
+use x86_64::{PhysAddr, VirtAddr, structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB}};
+
+use super::{CacheMode, MapError, vmalloc::{self, VmArea}};
+
+/// Device memory mapped into the VMALLOC window, unmapped again on drop.
+#[derive(Debug)]
+pub struct Mmio {
+    area: VmArea,
+    offset: u64,
+}
+
+impl Mmio {
+    /// Where the physical address passed to `map` ended up.
+    pub fn addr(&self) -> VirtAddr {
+        self.area.start() + self.offset
+    }
+
+    /// Keep the registers mapped forever.
+    pub fn leak(self) -> VirtAddr {
+        self.area.leak() + self.offset
+    }
+}
+
+/// Map `size` bytes of device memory at `phys`.
+pub fn map(phys: PhysAddr, size: u64, cache: CacheMode) -> Result<Mmio, MapError> {
+    let offset = phys.as_u64() % Page::<Size4KiB>::SIZE;
+    let frame: PhysFrame = PhysFrame::containing_address(phys);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | cache.flags();
+
+    let area = unsafe { vmalloc::map(frame, offset + size, flags)? };
+
+    Ok(Mmio {
+        area,
+        offset,
+    })
+}
@@ -1,28 +1,81 @@
-use bootinfo::boot_info::MemoryMap;
-use spinning_top::{Spinlock, SpinlockGuard, const_spinlock};
+use bootinfo::{boot_info::{FrameBuffer, MemoryMap, MemoryType}, memory_layout::PHYSMAP_BASE};
+use core::fmt;
+use x86_64::{PhysAddr, VirtAddr};
 
-mod phys;
-mod mapper;
+pub mod phys;
+pub mod mapper;
 mod heap;
+pub mod stack;
+pub mod mmio;
+pub mod tlb;
+pub mod pat;
+pub mod vmalloc;
 
-pub struct Locked<A> {
-    inner: Spinlock<A>,
+pub use self::{heap::HeapStats, mapper::{CacheMode, MapError}, phys::FrameStats};
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    pub frames: FrameStats,
+    pub page_table_frames: usize,
+    pub heap: HeapStats,
 }
 
-impl<A> Locked<A> {
-    pub const fn new(inner: A) -> Self {
-        Locked {
-            inner: const_spinlock(inner),
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const FRAMES_PER_MIB: usize = 256;
+
+        writeln!(f, "Memory: {} MiB usable, {} MiB free, {} MiB reserved, {} page table frames",
+            self.frames.total / FRAMES_PER_MIB,
+            self.frames.free / FRAMES_PER_MIB,
+            self.frames.reserved / FRAMES_PER_MIB,
+            self.page_table_frames,
+        )?;
+
+        for &memory_type in MemoryType::ALL.iter() {
+            let frames = self.frames.frames(memory_type);
+            if frames > 0 {
+                writeln!(f, "  {:?}: {} frames", memory_type, frames)?;
+            }
         }
+
+        write!(f, "Heap: {} KiB used, {} KiB peak, {} KiB mapped", self.heap.used / 1024, self.heap.peak / 1024, self.heap.mapped / 1024)
     }
+}
 
-    pub fn lock(&self) -> SpinlockGuard<A> {
-        self.inner.lock()
+/// Current frame and heap usage.
+pub fn stats() -> MemoryStats {
+    MemoryStats {
+        frames: phys::stats(),
+        page_table_frames: mapper::page_table_frames(),
+        heap: heap::stats(),
     }
 }
 
-pub fn init(map: &MemoryMap) {
+/// Give memory that only the bootloader and firmware needed to the frame allocator.
+/// Anything the kernel still needs from it has to be copied out before this.
+pub fn reclaim(map: &MemoryMap) {
+    let mut frames = 0;
+
+    for entry in map.entries().iter().filter(|entry| entry.memory_type.is_reclaimable()) {
+        let start = PhysAddr::new(entry.start);
+        unsafe {
+            frames += phys::release(start, start + entry.size);
+        }
+    }
+
+    crate::println!("Reclaimed {} KiB of boot memory", frames * 4);
+}
+
+/// Where physical memory can be reached through the physmap.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(phys.as_u64() + PHYSMAP_BASE)
+}
+
+pub fn init(map: &MemoryMap, framebuffer: &FrameBuffer) {
+    pat::init();
     phys::init(map);
-    mapper::init();
+    mapper::init(map, framebuffer);
+    tlb::init();
     heap::init();
+    vmalloc::init();
 }
\ No newline at end of file
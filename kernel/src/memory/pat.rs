@@ -0,0 +1,35 @@
+//! The page attribute table, which decides what the cache bits of a page table entry mean.
+//!
+//! The PWT, PCD and PAT bits of an entry pick one of the eight slots. The lower four keep their
+//! power-on meaning, so entries made by the firmware and bootloader don't change meaning.
+
+use x86_64::registers::model_specific::Msr;
+
+const IA32_PAT: u32 = 0x277;
+
+const UNCACHEABLE: u64 = 0x00;
+const WRITE_COMBINING: u64 = 0x01;
+const WRITE_THROUGH: u64 = 0x04;
+const WRITE_BACK: u64 = 0x06;
+const UNCACHED: u64 = 0x07;
+
+const SLOTS: [u64; 8] = [
+    WRITE_BACK,
+    WRITE_THROUGH,
+    UNCACHED,
+    UNCACHEABLE,
+    // Picked by the PAT bit on its own, see `mapper::CacheMode`.
+    WRITE_COMBINING,
+    WRITE_THROUGH,
+    UNCACHED,
+    UNCACHEABLE,
+];
+
+/// Program the PAT of the current CPU. Every CPU needs the same one.
+pub fn init() {
+    let value = SLOTS.iter().enumerate().fold(0, |value, (i, &ty)| value | ty << (i * 8));
+
+    unsafe {
+        Msr::new(IA32_PAT).write(value);
+    }
+}
@@ -1,85 +1,394 @@
-use bootinfo::{boot_info::{MemoryMap, MemoryType}, memory_layout::PHYSMAP_BASE};
-use x86_64::{PhysAddr, VirtAddr, structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB}};
+use bootinfo::boot_info::{MemoryMap, MemoryType};
+use x86_64::{PhysAddr, align_down, align_up, structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB}};
 
-use super::Locked;
+use crate::sync::Locked;
 
-struct StackElement {
-    next: Option<PhysAddr>,
+use super::phys_to_virt;
+
+/// The largest block is 2^MAX_ORDER frames, 4MiB.
+pub const MAX_ORDER: usize = 10;
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const LOW_LIMIT: u64 = 0x10_0000;
+const DMA32_LIMIT: u64 = 0x1_0000_0000;
+
+/// Which part of physical memory an allocation has to come from.
+// Nothing asks for the low zone yet, the trampoline uses `allocate_frame_below`.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    /// Below 1MiB, for code that runs in real mode.
+    Low,
+    /// Below 4GiB, for devices that can only do 32 bit DMA.
+    Dma32,
+    /// Anywhere.
+    Normal,
+}
+
+impl Zone {
+    fn limit(self) -> u64 {
+        match self {
+            Zone::Low => LOW_LIMIT,
+            Zone::Dma32 => DMA32_LIMIT,
+            Zone::Normal => u64::MAX,
+        }
+    }
 }
 
-fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
-    VirtAddr::new(phys.as_u64() + PHYSMAP_BASE)
+fn order_size(order: usize) -> u64 {
+    FRAME_SIZE << order
 }
 
-struct StackFrameAllocator {
+/// Lives at the start of every free block, through the physmap.
+struct FreeBlock {
+    prev: Option<PhysAddr>,
     next: Option<PhysAddr>,
-    count: usize,
 }
 
-impl StackFrameAllocator {
-    pub const fn new() -> Self {
-        StackFrameAllocator {
-            next: None,
-            count: 0,
+fn free_block(addr: PhysAddr) -> *mut FreeBlock {
+    phys_to_virt(addr).as_mut_ptr()
+}
+
+/// A buddy allocator for one zone.
+/// Free blocks are kept in a list per order, and marked in a bitmap so buddies can be found.
+struct Buddy {
+    // Blocks are aligned relative to `base`, which is aligned to the largest block.
+    base: u64,
+    start: u64,
+    end: u64,
+    free_lists: [Option<PhysAddr>; MAX_ORDER + 1],
+    bitmap: *mut u64,
+    free_frames: usize,
+}
+
+// The bitmap is only reachable through the allocator lock.
+unsafe impl Send for Buddy {}
+
+impl Buddy {
+    const fn new() -> Self {
+        Buddy {
+            base: 0,
+            start: 0,
+            end: 0,
+            free_lists: [None; MAX_ORDER + 1],
+            bitmap: core::ptr::null_mut(),
+            free_frames: 0,
         }
     }
-}
 
-unsafe impl FrameAllocator<Size4KiB> for StackFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<x86_64::structures::paging::PhysFrame<Size4KiB>> {
-        if let Some(next) = self.next {
-            
-            let element: *const StackElement = phys_to_virt(next).as_ptr();
-            let element = unsafe {&*element };
+    fn set_range(&mut self, start: u64, end: u64) {
+        self.base = align_down(start, order_size(MAX_ORDER));
+        self.start = start;
+        self.end = end.max(start);
+    }
+
+    fn blocks(&self, order: usize) -> usize {
+        let frames = ((self.end - self.base) / FRAME_SIZE) as usize;
+        (frames + (1 << order) - 1) >> order
+    }
 
-            self.next = element.next;
-            self.count -= 1;
+    /// How many bits the bitmap needs, one for every block of every order.
+    fn bitmap_bits(&self) -> usize {
+        (0..=MAX_ORDER).map(|order| self.blocks(order)).sum()
+    }
 
-            Some(PhysFrame::containing_address(next))
+    fn bit(&self, addr: PhysAddr, order: usize) -> usize {
+        let offset: usize = (0..order).map(|order| self.blocks(order)).sum();
+        offset + ((addr.as_u64() - self.base) >> (FRAME_SIZE.trailing_zeros() as usize + order)) as usize
+    }
+
+    unsafe fn is_free(&self, addr: PhysAddr, order: usize) -> bool {
+        let bit = self.bit(addr, order);
+        *self.bitmap.add(bit / 64) & (1 << (bit % 64)) != 0
+    }
+
+    unsafe fn set_free(&mut self, addr: PhysAddr, order: usize, free: bool) {
+        let bit = self.bit(addr, order);
+        let word = self.bitmap.add(bit / 64);
+        if free {
+            *word |= 1 << (bit % 64);
         } else {
-            None
+            *word &= !(1 << (bit % 64));
+        }
+    }
+
+    unsafe fn push(&mut self, addr: PhysAddr, order: usize) {
+        let head = self.free_lists[order];
+        free_block(addr).write(FreeBlock {
+            prev: None,
+            next: head,
+        });
+        if let Some(head) = head {
+            (*free_block(head)).prev = Some(addr);
+        }
+
+        self.free_lists[order] = Some(addr);
+        self.set_free(addr, order, true);
+    }
+
+    unsafe fn remove(&mut self, addr: PhysAddr, order: usize) {
+        let FreeBlock { prev, next } = free_block(addr).read();
+
+        match prev {
+            Some(prev) => (*free_block(prev)).next = next,
+            None => self.free_lists[order] = next,
+        }
+        if let Some(next) = next {
+            (*free_block(next)).prev = prev;
+        }
+
+        self.set_free(addr, order, false);
+    }
+
+    fn contains(&self, addr: u64, order: usize) -> bool {
+        addr >= self.start && addr + order_size(order) <= self.end
+    }
+
+    /// Free a block, merging it with its buddy for as long as that is free too.
+    unsafe fn free(&mut self, addr: PhysAddr, order: usize) {
+        self.free_frames += 1 << order;
+
+        let mut addr = addr.as_u64();
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = addr ^ order_size(order);
+            if !self.contains(buddy, order) || !self.is_free(PhysAddr::new(buddy), order) {
+                break;
+            }
+
+            self.remove(PhysAddr::new(buddy), order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+
+        self.push(PhysAddr::new(addr), order);
+    }
+
+    /// Allocate a block that ends at or below `limit`.
+    fn allocate(&mut self, order: usize, limit: u64) -> Option<PhysAddr> {
+        for mut current_order in order..=MAX_ORDER {
+            let mut current = self.free_lists[current_order];
+
+            while let Some(addr) = current {
+                unsafe {
+                    if addr.as_u64() + order_size(order) <= limit {
+                        self.remove(addr, current_order);
+
+                        // Keep the lower half, give back the upper halves.
+                        while current_order > order {
+                            current_order -= 1;
+                            self.push(addr + order_size(current_order), current_order);
+                        }
+
+                        self.free_frames -= 1 << order;
+                        return Some(addr);
+                    }
+
+                    current = (*free_block(addr)).next;
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Free all frames in `start..end`, in the largest blocks that fit.
+    unsafe fn add_range(&mut self, start: u64, end: u64) {
+        let mut start = align_up(start.max(self.start), FRAME_SIZE);
+        let end = align_down(end.min(self.end), FRAME_SIZE);
+
+        while start < end {
+            let order = (0..=MAX_ORDER).rev()
+                .find(|&order| start % order_size(order) == 0 && start + order_size(order) <= end)
+                .unwrap_or(0);
+
+            self.free(PhysAddr::new(start), order);
+            start += order_size(order);
         }
     }
 }
 
-impl FrameDeallocator<Size4KiB> for StackFrameAllocator {
-    unsafe fn deallocate_frame(&mut self, frame: x86_64::structures::paging::PhysFrame<Size4KiB>) {
-        let element: *mut StackElement = phys_to_virt(frame.start_address()).as_mut_ptr();
-        let element = &mut *element;
+/// Frame counts, for the whole of physical memory.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Managed by the frame allocator.
+    pub total: usize,
+    pub free: usize,
+    /// In the memory map, but not available to the frame allocator.
+    pub reserved: usize,
+    /// Everything in the memory map, indexed like `MemoryType::ALL`.
+    pub by_type: [usize; MemoryType::ALL.len()],
+}
+
+impl FrameStats {
+    pub fn frames(&self, memory_type: MemoryType) -> usize {
+        self.by_type[memory_type as usize]
+    }
+}
+
+/// One buddy allocator per zone, so zone constraints never need to split blocks.
+struct BuddyAllocator {
+    zones: [Buddy; 3],
+    total_frames: usize,
+    by_type: [usize; MemoryType::ALL.len()],
+}
 
-        element.next = self.next;
+impl BuddyAllocator {
+    const fn new() -> Self {
+        BuddyAllocator {
+            zones: [Buddy::new(), Buddy::new(), Buddy::new()],
+            total_frames: 0,
+            by_type: [0; MemoryType::ALL.len()],
+        }
+    }
 
-        self.next = Some(frame.start_address());
-        self.count += 1;
+    fn stats(&self) -> FrameStats {
+        let in_map: usize = self.by_type.iter().sum();
+        FrameStats {
+            total: self.total_frames,
+            free: self.zones.iter().map(|zone| zone.free_frames).sum(),
+            reserved: in_map - self.total_frames,
+            by_type: self.by_type,
+        }
+    }
+
+    /// Allocate from the highest zone that can satisfy `limit`, to keep low memory around.
+    fn allocate(&mut self, order: usize, limit: u64) -> Option<PhysAddr> {
+        assert!(order <= MAX_ORDER, "Order {} is too large", order);
+
+        self.zones.iter_mut().rev()
+            .filter(|zone| zone.start < limit)
+            .find_map(|zone| zone.allocate(order, limit))
+    }
+
+    unsafe fn deallocate(&mut self, addr: PhysAddr, order: usize) {
+        let zone = self.zones.iter_mut()
+            .find(|zone| zone.contains(addr.as_u64(), order))
+            .expect("Freeing a frame that was never allocated");
+
+        zone.free(addr, order);
     }
 }
 
-static FRAME_ALLOCATOR: Locked<StackFrameAllocator> = Locked::new(StackFrameAllocator::new());
+static FRAME_ALLOCATOR: Locked<BuddyAllocator> = Locked::new(BuddyAllocator::new());
+
+fn usable(map: &MemoryMap) -> impl Iterator<Item = (u64, u64)> + '_ {
+    map.entries().iter()
+        .filter(|entry| entry.memory_type.is_usable())
+        .map(|entry| (entry.start, entry.start + entry.size as u64))
+}
 
 pub fn init(map: &MemoryMap) {
-    for entry in map.entries() {
-        if entry.memory_type == MemoryType::Conventional {
-            let start = PhysFrame::containing_address(PhysAddr::new(entry.start));
-            let end = PhysFrame::containing_address(PhysAddr::new(entry.start + entry.size as u64));
-            for frame in PhysFrame::range(start, end) {
-                unsafe {
-                    FRAME_ALLOCATOR.lock().deallocate_frame(frame);
+    let mut allocator = FRAME_ALLOCATOR.lock();
+
+    let top = usable(map).map(|(_, end)| end).max().unwrap_or(0);
+    allocator.zones[0].set_range(0, LOW_LIMIT.min(top));
+    allocator.zones[1].set_range(LOW_LIMIT, DMA32_LIMIT.min(top));
+    allocator.zones[2].set_range(DMA32_LIMIT, top);
+
+    // The bitmaps are carved out of the first region above 1MiB that is big enough.
+    let bitmap_words: usize = allocator.zones.iter().map(|zone| (zone.bitmap_bits() + 63) / 64).sum();
+    let bitmap_size = align_up(bitmap_words as u64 * 8, FRAME_SIZE);
+    let bitmap_start = usable(map)
+        .map(|(start, end)| (align_up(start.max(LOW_LIMIT), FRAME_SIZE), end))
+        .find(|&(start, end)| end >= start + bitmap_size)
+        .map(|(start, _)| start)
+        .expect("No room for the frame allocator bitmaps");
+    let bitmap_end = bitmap_start + bitmap_size;
+
+    unsafe {
+        let mut bitmap: *mut u64 = phys_to_virt(PhysAddr::new(bitmap_start)).as_mut_ptr();
+        core::ptr::write_bytes(bitmap, 0, bitmap_words);
+        for zone in allocator.zones.iter_mut() {
+            zone.bitmap = bitmap;
+            bitmap = bitmap.add((zone.bitmap_bits() + 63) / 64);
+        }
+
+        for (start, end) in usable(map) {
+            for zone in allocator.zones.iter_mut() {
+                if start < bitmap_end && bitmap_start < end {
+                    zone.add_range(start, bitmap_start);
+                    zone.add_range(bitmap_end, end);
+                } else {
+                    zone.add_range(start, end);
                 }
             }
         }
     }
+
+    for entry in map.entries() {
+        allocator.by_type[entry.memory_type as usize] += entry.size / FRAME_SIZE as usize;
+    }
+    allocator.total_frames = allocator.zones.iter().map(|zone| zone.free_frames).sum();
+}
+
+/// Hand frames in `start..end` to the frame allocator, which it didn't manage before.
+/// Returns how many frames were added.
+pub unsafe fn release(start: PhysAddr, end: PhysAddr) -> usize {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+
+    let before: usize = allocator.zones.iter().map(|zone| zone.free_frames).sum();
+    for zone in allocator.zones.iter_mut() {
+        zone.add_range(start.as_u64(), end.as_u64());
+    }
+    let released = allocator.zones.iter().map(|zone| zone.free_frames).sum::<usize>() - before;
+
+    allocator.total_frames += released;
+    released
+}
+
+pub fn stats() -> FrameStats {
+    FRAME_ALLOCATOR.lock().stats()
 }
 
 pub struct PhysAlloc;
 
+impl PhysAlloc {
+    /// Allocate a frame below `limit`, for hardware that can't reach all of memory.
+    pub fn allocate_frame_below(&mut self, limit: PhysAddr) -> Option<PhysFrame<Size4KiB>> {
+        let addr = FRAME_ALLOCATOR.lock().allocate(0, limit.as_u64())?;
+        Some(PhysFrame::containing_address(addr))
+    }
+
+    /// Allocate 2^`order` physically contiguous frames, aligned to their size.
+    pub fn allocate_contiguous(&mut self, order: usize) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate_contiguous_in(order, Zone::Normal)
+    }
+
+    pub fn allocate_contiguous_in(&mut self, order: usize, zone: Zone) -> Option<PhysFrame<Size4KiB>> {
+        let addr = FRAME_ALLOCATOR.lock().allocate(order, zone.limit())?;
+        Some(PhysFrame::containing_address(addr))
+    }
+
+    /// Free frames from `allocate_contiguous`, `order` has to match.
+    pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame<Size4KiB>, order: usize) {
+        FRAME_ALLOCATOR.lock().deallocate(frame.start_address(), order)
+    }
+}
+
 unsafe impl FrameAllocator<Size4KiB> for PhysAlloc {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        FRAME_ALLOCATOR.lock().allocate_frame()
+        self.allocate_contiguous(0)
     }
 }
 
 impl FrameDeallocator<Size4KiB> for PhysAlloc {
-    unsafe fn deallocate_frame(&mut self, frame: x86_64::structures::paging::PhysFrame<Size4KiB>) {
-        FRAME_ALLOCATOR.lock().deallocate_frame(frame)
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate_contiguous(frame, 0)
     }
-}
\ No newline at end of file
+}
+
+const ORDER_2MIB: usize = 9;
+
+unsafe impl FrameAllocator<Size2MiB> for PhysAlloc {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let addr = FRAME_ALLOCATOR.lock().allocate(ORDER_2MIB, u64::MAX)?;
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for PhysAlloc {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        FRAME_ALLOCATOR.lock().deallocate(frame.start_address(), ORDER_2MIB)
+    }
+}
@@ -0,0 +1,40 @@
+use bootinfo::memory_layout::{STACK_BASE, STACK_GUARD, STACK_SIZE};
+use x86_64::{VirtAddr, structures::paging::PageTableFlags};
+
+use super::vmalloc::{self, VmArea};
+
+/// Which guard page was hit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuardHit {
+    BootStack,
+    /// The one below the VMALLOC area starting here, which is usually a kernel stack.
+    Vmalloc(VirtAddr),
+}
+
+/// A kernel stack in the VMALLOC window, with an unmapped guard page right below it.
+#[derive(Debug)]
+pub struct KernelStack {
+    area: VmArea,
+}
+
+impl KernelStack {
+    pub fn new() -> Option<KernelStack> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let area = vmalloc::allocate(STACK_SIZE, flags).ok()?;
+
+        Some(KernelStack { area })
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.area.end()
+    }
+}
+
+/// Check if `addr` lies in the guard page of the boot stack or of any VMALLOC area.
+pub fn guard_hit(addr: VirtAddr) -> Option<GuardHit> {
+    if (STACK_GUARD..STACK_BASE).contains(&addr.as_u64()) {
+        return Some(GuardHit::BootStack);
+    }
+
+    vmalloc::guard_hit(addr).map(GuardHit::Vmalloc)
+}
@@ -0,0 +1,95 @@
+//! Keeping the TLBs of all CPUs in line with the kernel page tables.
+//!
+//! Mapping a page that wasn't mapped needs no flush, but unmapping one or taking away
+//! permissions does, on every CPU that could have it cached.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{VirtAddr, instructions::tlb};
+
+use crate::{apic::local, interrupts::{InterruptFrame, TLB_SHOOTDOWN_VECTOR, register_handler}, percpu, smp};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Above this many pages, flushing everything is cheaper than going page by page.
+pub const FLUSH_ALL_PAGES: usize = 32;
+
+// The range of the shootdown in progress, and a bit for every CPU that still has to flush it.
+// There is only ever one shootdown at a time, as they happen with the kernel page table locked.
+static START: AtomicU64 = AtomicU64::new(0);
+static END: AtomicU64 = AtomicU64::new(0);
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+fn flush_local(start: u64, end: u64) {
+    if (end - start) / PAGE_SIZE > FLUSH_ALL_PAGES as u64 {
+        tlb::flush_all();
+    } else {
+        let mut addr = start;
+        while addr < end {
+            tlb::flush(VirtAddr::new(addr));
+            addr += PAGE_SIZE;
+        }
+    }
+}
+
+/// The bit of the current CPU in `PENDING`, none if it isn't set up yet.
+fn cpu_bit() -> u64 {
+    if percpu::is_initialized() {
+        1 << smp::current().index
+    } else {
+        0
+    }
+}
+
+/// Flush `start..end` on every online CPU, and wait until they all did.
+/// Only call this with the kernel page table locked.
+pub fn flush(start: VirtAddr, end: VirtAddr) {
+    let (start, end) = (start.as_u64(), end.as_u64());
+    if start >= end {
+        return;
+    }
+
+    flush_local(start, end);
+
+    let online = smp::cpus_online();
+    if online <= 1 {
+        return;
+    }
+
+    // Online CPUs always have the lowest indices.
+    let others = (u64::MAX >> (64 - online)) & !cpu_bit();
+    START.store(start, Ordering::Relaxed);
+    END.store(end, Ordering::Relaxed);
+    PENDING.store(others, Ordering::Release);
+
+    unsafe {
+        local::send_ipi(0, local::ICR_ALL_EXCLUDING_SELF | TLB_SHOOTDOWN_VECTOR as u32);
+    }
+
+    // Nobody can be waiting on us, they would need the kernel page table for that.
+    while PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Do the flush this CPU was asked to do, if any.
+/// Called by the interrupt, and through `sync::relax` by every loop that might spin with
+/// interrupts disabled, as the CPU we'd be waiting for could be waiting for us.
+pub fn service() {
+    if smp::cpus_online() <= 1 {
+        return;
+    }
+
+    let bit = cpu_bit();
+    if PENDING.load(Ordering::Acquire) & bit != 0 {
+        flush_local(START.load(Ordering::Relaxed), END.load(Ordering::Relaxed));
+        PENDING.fetch_and(!bit, Ordering::Release);
+    }
+}
+
+fn shootdown(_frame: &mut InterruptFrame) {
+    service();
+}
+
+pub fn init() {
+    register_handler(TLB_SHOOTDOWN_VECTOR, shootdown);
+}
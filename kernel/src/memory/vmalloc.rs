@@ -0,0 +1,171 @@
+//! Virtual ranges in the VMALLOC window, for anything that gets mapped after boot.
+//!
+//! Every range starts with an unmapped guard page, so running off the start of a range,
+//! or off the end into the next one, faults instead of silently corrupting memory.
+
+use alloc::vec::Vec;
+use bootinfo::memory_layout::{VMALLOC_BASE, VMALLOC_GUARD_SIZE, VMALLOC_TOP};
+use x86_64::{VirtAddr, structures::paging::{Page, PageTableFlags, PhysFrame, page::PageRange}};
+
+use crate::sync::Locked;
+
+use super::{MapError, mapper};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+struct VmAllocator {
+    // Both sorted by start address, ranges in `used` include their guard page.
+    free: Vec<(u64, u64)>,
+    used: Vec<(u64, u64)>,
+}
+
+impl VmAllocator {
+    const fn new() -> Self {
+        VmAllocator {
+            free: Vec::new(),
+            used: Vec::new(),
+        }
+    }
+
+    /// Returns the start of `size` bytes, right after a guard page.
+    fn allocate(&mut self, size: u64) -> Option<u64> {
+        let needed = VMALLOC_GUARD_SIZE + size;
+        let i = self.free.iter().position(|&(start, end)| end - start >= needed)?;
+
+        let (start, end) = self.free[i];
+        if end - start == needed {
+            self.free.remove(i);
+        } else {
+            self.free[i].0 = start + needed;
+        }
+
+        let j = self.used.binary_search_by_key(&start, |&(start, _)| start).unwrap_err();
+        self.used.insert(j, (start, start + needed));
+
+        Some(start + VMALLOC_GUARD_SIZE)
+    }
+
+    fn deallocate(&mut self, addr: u64) {
+        let j = self.used.binary_search_by_key(&(addr - VMALLOC_GUARD_SIZE), |&(start, _)| start).expect("Range was not allocated");
+        let (start, end) = self.used.remove(j);
+
+        let i = self.free.binary_search_by_key(&start, |&(start, _)| start).unwrap_err();
+        self.free.insert(i, (start, end));
+
+        // Merge with the free ranges on either side.
+        if i + 1 < self.free.len() && self.free[i + 1].0 == end {
+            self.free[i].1 = self.free[i + 1].1;
+            self.free.remove(i + 1);
+        }
+        if i > 0 && self.free[i - 1].1 == start {
+            self.free[i - 1].1 = self.free[i].1;
+            self.free.remove(i);
+        }
+    }
+
+    /// The start of the range whose guard page `addr` is in.
+    fn guard_hit(&self, addr: u64) -> Option<u64> {
+        let j = match self.used.binary_search_by_key(&addr, |&(start, _)| start) {
+            Ok(j) => j,
+            Err(0) => return None,
+            Err(j) => j - 1,
+        };
+
+        let (start, _) = self.used[j];
+        if addr < start + VMALLOC_GUARD_SIZE {
+            Some(start + VMALLOC_GUARD_SIZE)
+        } else {
+            None
+        }
+    }
+}
+
+static ALLOCATOR: Locked<VmAllocator> = Locked::new(VmAllocator::new());
+
+/// A mapped range of the VMALLOC window, unmapped and freed again on drop.
+#[derive(Debug)]
+pub struct VmArea {
+    start: VirtAddr,
+    size: u64,
+    // Whether the frames were allocated for this area, and should be freed with it.
+    owns_frames: bool,
+}
+
+impl VmArea {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    fn pages(&self) -> PageRange {
+        let start = Page::containing_address(self.start);
+        Page::range(start, start + self.size / PAGE_SIZE)
+    }
+
+    /// Keep the area mapped forever.
+    pub fn leak(self) -> VirtAddr {
+        let start = self.start;
+        core::mem::forget(self);
+        start
+    }
+}
+
+impl Drop for VmArea {
+    fn drop(&mut self) {
+        unsafe {
+            if self.owns_frames {
+                mapper::unmap_and_free(self.pages())
+            } else {
+                mapper::unmap_range(self.pages())
+            }.expect("VMALLOC area was not mapped");
+        }
+
+        ALLOCATOR.lock().deallocate(self.start.as_u64());
+    }
+}
+
+/// Reserve `size` bytes, rounded up to whole pages, and map them with `map`.
+fn allocate_with(size: u64, owns_frames: bool, map: impl FnOnce(PageRange) -> Result<(), MapError>) -> Result<VmArea, MapError> {
+    let size = x86_64::align_up(size.max(1), PAGE_SIZE);
+    let start = ALLOCATOR.lock().allocate(size).ok_or(MapError::NoVirtualSpace)?;
+
+    let first = Page::containing_address(VirtAddr::new(start));
+    if let Err(err) = map(Page::range(first, first + size / PAGE_SIZE)) {
+        ALLOCATOR.lock().deallocate(start);
+        return Err(err);
+    }
+
+    Ok(VmArea {
+        start: VirtAddr::new(start),
+        size,
+        owns_frames,
+    })
+}
+
+/// Map `size` bytes of newly allocated memory.
+pub fn allocate(size: u64, flags: PageTableFlags) -> Result<VmArea, MapError> {
+    allocate_with(size, true, |pages| unsafe { mapper::map_allocated(pages, flags) })
+}
+
+/// Map `size` bytes of physical memory starting at `frame`, which stays owned by the caller.
+pub unsafe fn map(frame: PhysFrame, size: u64, flags: PageTableFlags) -> Result<VmArea, MapError> {
+    allocate_with(size, false, |pages| mapper::map_range(pages, frame, flags))
+}
+
+/// If `addr` is in the guard page of an area, the start of that area.
+/// Safe to call from the page fault handler, it gives up if the allocator is busy.
+pub fn guard_hit(addr: VirtAddr) -> Option<VirtAddr> {
+    if !(VMALLOC_BASE..VMALLOC_TOP).contains(&addr.as_u64()) {
+        return None;
+    }
+
+    let allocator = ALLOCATOR.try_lock()?;
+    allocator.guard_hit(addr.as_u64()).map(VirtAddr::new)
+}
+
+pub fn init() {
+    ALLOCATOR.lock().free.push((VMALLOC_BASE, VMALLOC_TOP));
+}
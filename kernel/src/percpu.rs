@@ -0,0 +1,155 @@
+//! Per-CPU variables.
+//!
+//! Statics declared with `percpu!` end up in the `percpu` section, which is the template
+//! for the area every CPU gets a copy of. While in the kernel, GS base points at the area
+//! of the current CPU. User mode gets its own GS base, which the interrupt entry swaps in
+//! and out with `swapgs`.
+
+use core::{alloc::Layout, marker::PhantomData, ops::Deref};
+use x86_64::{VirtAddr, instructions::interrupts, registers::model_specific::{GsBase, KernelGsBase}};
+
+use crate::smp::Cpu;
+
+extern "C" {
+    static __start_percpu: u8;
+    static __stop_percpu: u8;
+}
+
+/// Sits at the start of every area, the variables follow it.
+#[repr(C)]
+struct Header {
+    // Has to be the first field, `area` reads it from gs:0.
+    this: *mut Header,
+    // Read from gs:8 by `smp::current`.
+    cpu: *const Cpu,
+}
+
+const HEADER_SIZE: usize = 64;
+const AREA_ALIGN: usize = 0x1000;
+
+fn template() -> &'static [u8] {
+    unsafe {
+        let start = &__start_percpu as *const u8;
+        let len = &__stop_percpu as *const u8 as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+/// The area of the current CPU.
+fn area() -> *mut u8 {
+    unsafe {
+        let area: *mut u8;
+        asm!("mov {}, gs:[0]", out(reg) area, options(nostack, readonly, preserves_flags));
+        area
+    }
+}
+
+/// Give the current CPU its own copy of all per-CPU variables and point GS base at it.
+/// This needs the heap, and has to happen once on every CPU before any per-CPU variable is used.
+pub unsafe fn init(cpu: &'static Cpu) {
+    let template = template();
+    let layout = Layout::from_size_align(HEADER_SIZE + template.len(), AREA_ALIGN).unwrap();
+
+    // CPUs never go away, so neither do their areas.
+    let area = alloc::alloc::alloc(layout);
+    assert!(!area.is_null(), "Could not allocate per-CPU area");
+
+    core::ptr::copy_nonoverlapping(template.as_ptr(), area.add(HEADER_SIZE), template.len());
+
+    let header = area as *mut Header;
+    header.write(Header {
+        this: header,
+        cpu,
+    });
+
+    GsBase::write(VirtAddr::from_ptr(area));
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// Whether `init` ran on the current CPU.
+pub fn is_initialized() -> bool {
+    GsBase::read() != VirtAddr::zero()
+}
+
+/// A variable with a separate copy for every CPU, declared with `percpu!`.
+pub struct PerCpu<T> {
+    template: T,
+}
+
+// Every CPU only ever touches its own copy, with interrupts disabled.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        PerCpu {
+            template: value,
+        }
+    }
+
+    fn current(&'static self) -> *const T {
+        let offset = &self.template as *const T as usize - template().as_ptr() as usize;
+        unsafe { area().add(HEADER_SIZE + offset) as *const T }
+    }
+
+    /// The copy of the current CPU. Interrupts are disabled until the guard is dropped,
+    /// so we can't be moved to another CPU while using it.
+    pub fn get(&'static self) -> PerCpuGuard<T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        PerCpuGuard {
+            value: unsafe { &*self.current() },
+            enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Call `f` with the copy of the current CPU.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.get())
+    }
+}
+
+pub struct PerCpuGuard<T: 'static> {
+    value: &'static T,
+    enabled: bool,
+    // The guard only makes sense on the CPU that created it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for PerCpuGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for PerCpuGuard<T> {
+    fn drop(&mut self) {
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Declare per-CPU statics. Use `Cell` or `RefCell` for anything that needs to change.
+///
+/// ```ignore
+/// percpu! {
+///     static TICKS: Cell<u64> = Cell::new(0);
+/// }
+///
+/// TICKS.with(|ticks| ticks.set(ticks.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            #[link_section = "percpu"]
+            $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new($init);
+        )*
+    };
+}
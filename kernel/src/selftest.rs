@@ -0,0 +1,223 @@
+//! A quick run through the kernel's mappings, user copies, the thread API, timed sleeps, IRQ
+//! routing and the blocking locks at boot, so a broken page table or scheduler shows up right away instead of
+//! whenever something first relies on it.
+
+use alloc::{sync::Arc, vec::Vec};
+use bootinfo::memory_layout::{STACK_GUARD, STACK_SIZE};
+use core::time::Duration;
+use x86_64::{VirtAddr, structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags, PhysFrame}};
+
+use crate::{memory::{mapper, phys::PhysAlloc, phys_to_virt, stack::{self, GuardHit, KernelStack}}, println, sync::{Condvar, Mutex, RwLock, Semaphore, TicketLock}, thread, time::{self, Instant, rtc}, user::{self, UserAccessError}};
+
+const THREADS: u64 = 4;
+const YIELDS: usize = 3;
+const ROUNDS: u64 = 10;
+const SLEEP: Duration = Duration::from_millis(50);
+/// Somewhere in the lower half that nothing else maps.
+const USER_PAGE: u64 = 0x0000_4000_0000_0000;
+const MESSAGE: &[u8] = b"copied through user memory";
+
+pub fn run() {
+    mappings();
+    user_copies();
+    threads();
+    sleep();
+    irq();
+    spinlocks();
+    mutex();
+    semaphore();
+    condvar();
+}
+
+/// Code isn't writable, data isn't executable, the physmap maps what it should and nothing is
+/// mapped in the stack guard pages.
+fn mappings() {
+    let flags = |addr: VirtAddr| mapper::query(addr).map(|mapping| mapping.flags).unwrap_or_else(|| panic!("{:#X} is not mapped", addr.as_u64()));
+    let writable_or_nx = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let text = flags(VirtAddr::new(mappings as fn() as usize as u64));
+    assert!(!text.intersects(writable_or_nx), "Kernel code is writable or not executable: {:?}", text);
+
+    let local = 0u64;
+    let data = flags(VirtAddr::from_ptr(&local));
+    assert!(data.contains(writable_or_nx), "Kernel stack is read only or executable: {:?}", data);
+
+    let frame: PhysFrame = PhysAlloc.allocate_frame().expect("Could not allocate a frame");
+    let phys = mapper::translate(phys_to_virt(frame.start_address()));
+    unsafe {
+        PhysAlloc.deallocate_frame(frame);
+    }
+    assert_eq!(phys, Some(frame.start_address()), "Physmap maps the wrong frame");
+
+    let kernel_stack = KernelStack::new().expect("Could not allocate a kernel stack");
+    let bottom = kernel_stack.top() - STACK_SIZE;
+    for &(guard, hit) in [(VirtAddr::new(STACK_GUARD), GuardHit::BootStack), (bottom - 1u64, GuardHit::Vmalloc(bottom))].iter() {
+        assert!(mapper::query(guard).is_none(), "Stack guard page at {:#X} is mapped", guard.as_u64());
+        assert_eq!(stack::guard_hit(guard), Some(hit), "Stack guard page at {:#X} is not recognized", guard.as_u64());
+    }
+
+    println!("Self-check: kernel mappings are W^X and stack guard pages are unmapped");
+}
+
+/// Copy to and from a user page, through SMAP if the CPU has it, and check that a copy from
+/// the page once it is unmapped fails instead of taking the kernel down.
+fn user_copies() {
+    let page = Page::containing_address(VirtAddr::new(USER_PAGE));
+    let pages = Page::range(page, page + 1);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+    unsafe {
+        mapper::map_allocated(pages, flags).expect("Could not map a user page");
+    }
+
+    let mut copy = [0u8; MESSAGE.len()];
+    user::copy_to_user(page.start_address(), MESSAGE).expect("Could not copy to a user page");
+    user::copy_from_user(&mut copy, page.start_address()).expect("Could not copy from a user page");
+    assert_eq!(&copy[..], MESSAGE, "User page lost what was copied to it");
+
+    unsafe {
+        mapper::unmap_and_free(pages).expect("User page was not mapped");
+    }
+    assert_eq!(user::copy_from_user(&mut copy, page.start_address()), Err(UserAccessError::BadAddress), "Copy from an unmapped user page succeeded");
+
+    println!("Self-check: user copies work{} and fail cleanly on unmapped pages", if user::smap() { " under SMAP" } else { "" });
+}
+
+/// Spawn a few threads that sleep and yield, and join them all.
+fn threads() {
+    let handles: Vec<_> = (0..THREADS)
+        .map(|i| thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10 * (i + 1)));
+            for _ in 0..YIELDS {
+                thread::yield_now();
+            }
+            i * i
+        }))
+        .collect();
+
+    for (i, handle) in (0..THREADS).zip(handles) {
+        let id = handle.thread().id();
+        let cpu = handle.thread().cpu();
+        assert_eq!(handle.join(), i * i, "Thread {} returned the wrong result", id);
+        println!("Self-check: thread {} on CPU {} slept, yielded and was joined", id, cpu);
+    }
+}
+
+/// Sleep on the timer, and check the clock agrees it was long enough.
+fn sleep() {
+    let start = Instant::now();
+    time::sleep(SLEEP);
+    let slept = start.elapsed();
+
+    assert!(slept >= SLEEP, "Asked to sleep for {:?}, woke up after {:?}", SLEEP, slept);
+    println!("Self-check: slept for {:?}, {:?} since boot", slept, Instant::now().since_boot());
+}
+
+/// Route the RTC interrupt through the I/O APIC, and see it come in.
+fn irq() {
+    if let Err(err) = rtc::start_periodic() {
+        return println!("Self-check: RTC interrupt can't be routed: {:?}", err);
+    }
+
+    let start = rtc::periodic_ticks();
+    time::sleep(SLEEP);
+    let ticks = rtc::periodic_ticks() - start;
+    rtc::stop_periodic();
+
+    assert!(ticks > 0, "No RTC interrupts in {:?}", SLEEP);
+    println!("Self-check: {} RTC interrupts in {:?}", ticks, SLEEP);
+}
+
+/// The try variants of the spinlocks fail while the lock is held in a conflicting way.
+fn spinlocks() {
+    let ticket = TicketLock::new(());
+    let guard = ticket.lock();
+    assert!(ticket.try_lock().is_none(), "Ticket lock was taken twice");
+    drop(guard);
+    assert!(ticket.try_lock().is_some(), "Ticket lock was not released");
+
+    let rw = RwLock::new(());
+    let reader = rw.read();
+    assert!(rw.try_read().is_some(), "Readers can't share the RW lock");
+    assert!(rw.try_write().is_none(), "RW lock let a writer in with a reader");
+    drop(reader);
+    let writer = rw.write();
+    assert!(rw.try_read().is_none(), "RW lock let a reader in with a writer");
+    drop(writer);
+    assert!(rw.try_write().is_some(), "RW lock was not released");
+}
+
+/// Threads that yield while holding the mutex, so the others have to block on it.
+fn mutex() {
+    let count = Arc::new(Mutex::new(0));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let count = count.clone();
+            thread::spawn(move || {
+                for _ in 0..ROUNDS {
+                    let mut count = count.lock();
+                    let seen = *count;
+                    thread::yield_now();
+                    *count = seen + 1;
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join();
+    }
+
+    assert_eq!(*count.lock(), THREADS * ROUNDS, "Mutex let more than one thread in");
+    println!("Self-check: {} threads took turns on a mutex", THREADS);
+}
+
+/// Wait for permits that other threads hand out a while later.
+fn semaphore() {
+    let permits = Arc::new(Semaphore::new(0));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let permits = permits.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(5 * (i + 1)));
+                permits.release();
+            })
+        })
+        .collect();
+
+    for _ in 0..THREADS {
+        permits.acquire();
+    }
+    for handle in handles {
+        handle.join();
+    }
+
+    assert_eq!(permits.available(), 0, "Semaphore has permits left over");
+    println!("Self-check: waited for {} semaphore permits", THREADS);
+}
+
+/// Two threads that each wait on the condition variable for the other to take its turn.
+fn condvar() {
+    let turns = Arc::new((Mutex::new(0), Condvar::new()));
+    let other = turns.clone();
+
+    let handle = thread::spawn(move || take_turns(&other, 1, Condvar::notify_one));
+    take_turns(&turns, 0, Condvar::notify_all);
+    handle.join();
+
+    assert_eq!(*turns.0.lock(), 2 * ROUNDS, "Turns went missing");
+    println!("Self-check: two threads took {} turns through a condition variable", 2 * ROUNDS);
+}
+
+/// Take every turn where the count has `parity`, and tell the other thread with `notify`.
+fn take_turns(turns: &(Mutex<u64>, Condvar), parity: u64, notify: fn(&Condvar)) {
+    let (turn, changed) = turns;
+    let mut turn = turn.lock();
+
+    for _ in 0..ROUNDS {
+        while *turn % 2 != parity {
+            turn = changed.wait(turn);
+        }
+        *turn += 1;
+        notify(changed);
+    }
+}
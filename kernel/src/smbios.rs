@@ -0,0 +1,111 @@
+use core::slice;
+use x86_64::PhysAddr;
+
+use crate::{memory::phys_to_virt, sync::Locked};
+
+const ANCHOR: &[u8; 5] = b"_SM3_";
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_END: u8 = 127;
+
+#[derive(Debug)]
+pub enum SmbiosError {
+    NoEntryPoint,
+    InvalidAnchor,
+    InvalidChecksum,
+}
+
+/// The SMBIOS 3 (64-bit) entry point structure.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct EntryPoint {
+    pub anchor: [u8; 5],
+    pub checksum: u8,
+    pub length: u8,
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub docrev: u8,
+    pub revision: u8,
+    pub reserved: u8,
+    pub table_max_size: u32,
+    pub table_address: u64,
+}
+
+static ENTRY_POINT: Locked<Option<EntryPoint>> = Locked::new(None);
+
+pub fn entry_point() -> Option<EntryPoint> {
+    *ENTRY_POINT.lock()
+}
+
+/// The raw structure table the entry point refers to.
+pub fn structure_table() -> Option<&'static [u8]> {
+    let entry_point = entry_point()?;
+    let table = phys_to_virt(PhysAddr::new(entry_point.table_address));
+
+    Some(unsafe { slice::from_raw_parts(table.as_ptr(), entry_point.table_max_size as usize) })
+}
+
+/// One structure from the table: the formatted area, header included, and the strings after it.
+struct Structure {
+    formatted: &'static [u8],
+    strings: &'static [u8],
+}
+
+impl Structure {
+    fn kind(&self) -> u8 {
+        self.formatted[0]
+    }
+
+    /// The string a byte at `offset` of the formatted area refers to, counting from 1.
+    fn string(&self, offset: usize) -> Option<&'static str> {
+        let index = *self.formatted.get(offset)? as usize;
+        let string = self.strings.split(|byte| *byte == 0).nth(index.checked_sub(1)?)?;
+        core::str::from_utf8(string).ok().map(str::trim).filter(|string| !string.is_empty())
+    }
+}
+
+fn structures() -> impl Iterator<Item = Structure> {
+    let mut table = structure_table().unwrap_or(&[]);
+
+    core::iter::from_fn(move || {
+        let length = *table.get(1)? as usize;
+        if length < 4 || length > table.len() {
+            return None;
+        }
+
+        // The string set ends with two NULs, even when it is empty.
+        let end = (length..table.len().saturating_sub(1)).find(|&i| table[i] == 0 && table[i + 1] == 0)?;
+        let structure = Structure { formatted: &table[..length], strings: &table[length..end] };
+        table = &table[end + 2..];
+
+        Some(structure)
+    })
+    .take_while(|structure| structure.kind() != TYPE_END)
+}
+
+/// The system vendor and product name from the System Information structure.
+pub fn system() -> Option<(&'static str, &'static str)> {
+    let system = structures().find(|structure| structure.kind() == TYPE_SYSTEM)?;
+
+    Some((system.string(4).unwrap_or("unknown vendor"), system.string(5).unwrap_or("unknown product")))
+}
+
+pub fn init(address: Option<u64>) -> Result<(), SmbiosError> {
+    let address = address.ok_or(SmbiosError::NoEntryPoint)?;
+    let ptr = phys_to_virt(PhysAddr::new(address)).as_ptr::<u8>();
+    let entry_point: EntryPoint = unsafe { core::ptr::read_unaligned(ptr as *const EntryPoint) };
+
+    if &entry_point.anchor != ANCHOR {
+        return Err(SmbiosError::InvalidAnchor);
+    }
+
+    let bytes = unsafe { slice::from_raw_parts(ptr, entry_point.length as usize) };
+    if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err(SmbiosError::InvalidChecksum);
+    }
+
+    crate::println!("SMBIOS: version {}.{}", entry_point.major_version, entry_point.minor_version);
+
+    *ENTRY_POINT.lock() = Some(entry_point);
+
+    Ok(())
+}
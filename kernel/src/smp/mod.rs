@@ -0,0 +1,201 @@
+use core::{convert::TryFrom, sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering}};
+use x86_64::{PhysAddr, VirtAddr, registers::{control::{Cr0, Cr3, Cr4}, model_specific::Efer}, structures::paging::{FrameDeallocator, Page, PageTableFlags}};
+
+use crate::{acpi::madt::Madt, apic::local, gdt, interrupts::SPURIOUS_VECTOR, memory::{mapper, pat, phys::PhysAlloc, phys_to_virt, stack::KernelStack}, percpu, println, sync::relax, thread, time::{self, Duration}};
+
+use self::trampoline::Trampoline;
+
+mod trampoline;
+
+pub const MAX_CPUS: usize = 64;
+
+/// How long to wait for an application processor to show up after the startup IPIs.
+const STARTUP_TIMEOUT_MS: u64 = 100;
+
+// Whether the processor being started got going, or we gave up on it.
+// Whoever gets to change it from pending first wins, so a late processor can't run on a freed stack.
+const STARTUP_PENDING: u8 = 0;
+const STARTUP_STARTED: u8 = 1;
+const STARTUP_ABANDONED: u8 = 2;
+
+static STARTUP: AtomicU8 = AtomicU8::new(STARTUP_PENDING);
+
+pub struct Cpu {
+    pub index: usize,
+    pub apic_id: u32,
+    stack: Option<KernelStack>,
+}
+
+impl Cpu {
+    const fn new() -> Self {
+        Cpu {
+            index: 0,
+            apic_id: 0,
+            stack: None,
+        }
+    }
+}
+
+const OFFLINE_CPU: Cpu = Cpu::new();
+
+// Each entry is only written by the CPU that is bringing it online, before it is online.
+static mut CPUS: [Cpu; MAX_CPUS] = [OFFLINE_CPU; MAX_CPUS];
+static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+// The control registers of the bootstrap processor, which the others copy.
+static BSP_CR0: AtomicU64 = AtomicU64::new(0);
+static BSP_CR4: AtomicU64 = AtomicU64::new(0);
+static BSP_EFER: AtomicU64 = AtomicU64::new(0);
+
+/// The CPU we are currently running on.
+pub fn current() -> &'static Cpu {
+    unsafe {
+        let cpu: *const Cpu;
+        // The per-CPU area points back at its `Cpu`.
+        asm!("mov {}, gs:[8]", out(reg) cpu, options(nostack, readonly, preserves_flags));
+        &*cpu
+    }
+}
+
+/// The CPU with `index`, only meaningful once it is online.
+pub fn cpu(index: usize) -> &'static Cpu {
+    unsafe { &CPUS[index] }
+}
+
+pub fn cpus_online() -> usize {
+    CPUS_ONLINE.load(Ordering::Acquire)
+}
+
+/// Make `index` the current CPU and mark it online.
+unsafe fn set_current(index: usize) {
+    let cpu = &mut CPUS[index];
+    cpu.index = index;
+    cpu.apic_id = local::id();
+
+    percpu::init(&CPUS[index]);
+
+    println!("CPU {} (APIC {}) online", index, cpu.apic_id);
+    CPUS_ONLINE.fetch_add(1, Ordering::Release);
+}
+
+/// Set up the per-CPU state of the bootstrap processor.
+pub fn init() {
+    unsafe {
+        set_current(0);
+    }
+}
+
+extern "C" fn ap_entry(index: usize) -> ! {
+    if STARTUP.compare_exchange(STARTUP_PENDING, STARTUP_STARTED, Ordering::AcqRel, Ordering::Acquire).is_err() {
+        // Too late, the INIT that parks us is on its way.
+        loop {
+            x86_64::instructions::hlt();
+        }
+    }
+
+    unsafe {
+        Cr0::write_raw(BSP_CR0.load(Ordering::Relaxed));
+        Cr4::write_raw(BSP_CR4.load(Ordering::Relaxed));
+        Efer::write_raw(BSP_EFER.load(Ordering::Relaxed));
+    }
+    pat::init();
+
+    gdt::init_ap(index);
+    crate::interrupts::init_ap();
+    local::init(SPURIOUS_VECTOR);
+
+    unsafe {
+        set_current(index);
+    }
+
+    thread::init_cpu();
+    thread::exit();
+}
+
+/// Start every processor listed in the MADT, using INIT-SIPI-SIPI.
+pub fn start_aps() {
+    let madt = match Madt::get() {
+        Some(madt) => madt,
+        None => return,
+    };
+
+    BSP_CR0.store(Cr0::read_raw(), Ordering::Relaxed);
+    BSP_CR4.store(Cr4::read_raw(), Ordering::Relaxed);
+    BSP_EFER.store(Efer::read_raw(), Ordering::Relaxed);
+
+    let frame = PhysAlloc.allocate_frame_below(PhysAddr::new(0x10_0000)).expect("No memory below 1MiB for the AP trampoline");
+    let base = frame.start_address().as_u64();
+
+    // The trampoline turns on paging while running from its physical address.
+    let identity: Page = Page::containing_address(VirtAddr::new(base));
+    unsafe {
+        mapper::map_page(identity, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE).expect("Could not map the AP trampoline");
+    }
+
+    // `mapper::init` puts the kernel page table below 4GiB for the trampoline.
+    let (l4, _) = Cr3::read();
+    let cr3 = u32::try_from(l4.start_address().as_u64()).expect("Kernel page table is above 4GiB");
+    let trampoline = unsafe {
+        Trampoline::install(base, phys_to_virt(frame.start_address()).as_mut_ptr(), cr3)
+    };
+
+    let bsp_apic_id = local::id();
+    let mut next_index = 1;
+
+    for processor in madt.processors().filter(|p| p.enabled && p.apic_id != bsp_apic_id) {
+        if next_index >= MAX_CPUS {
+            println!("Ignoring CPUs beyond {}", MAX_CPUS);
+            break;
+        }
+
+        let index = next_index;
+        let stack = KernelStack::new().expect("Could not allocate AP stack");
+        trampoline.prepare(stack.top().as_u64(), ap_entry as usize as u64, index as u64);
+
+        unsafe {
+            CPUS[index].stack = Some(stack);
+        }
+
+        let online = cpus_online();
+        STARTUP.store(STARTUP_PENDING, Ordering::Release);
+
+        unsafe {
+            local::send_ipi(processor.apic_id, local::ICR_INIT);
+            time::delay(Duration::from_millis(10));
+            local::send_ipi(processor.apic_id, local::ICR_STARTUP | trampoline.vector() as u32);
+            time::delay(Duration::from_micros(200));
+            if STARTUP.load(Ordering::Acquire) == STARTUP_PENDING {
+                local::send_ipi(processor.apic_id, local::ICR_STARTUP | trampoline.vector() as u32);
+            }
+        }
+
+        // The trampoline is shared, so wait for this processor before starting the next.
+        let mut waited = 0;
+        while STARTUP.load(Ordering::Acquire) == STARTUP_PENDING && waited < STARTUP_TIMEOUT_MS {
+            time::delay(Duration::from_millis(1));
+            waited += 1;
+        }
+
+        if STARTUP.compare_exchange(STARTUP_PENDING, STARTUP_ABANDONED, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+            println!("CPU with APIC {} did not start", processor.apic_id);
+
+            // It might still be on its way through the trampoline, park it before its stack goes.
+            unsafe {
+                local::send_ipi(processor.apic_id, local::ICR_INIT);
+                time::delay(Duration::from_millis(10));
+                CPUS[index].stack = None;
+            }
+        } else {
+            while cpus_online() == online {
+                relax();
+            }
+            next_index += 1;
+        }
+    }
+
+    // Every processor that didn't start was parked, so nothing runs the trampoline anymore.
+    unsafe {
+        mapper::unmap_page(identity).expect("AP trampoline was not mapped");
+        PhysAlloc.deallocate_frame(frame);
+    }
+}
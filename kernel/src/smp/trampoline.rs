@@ -0,0 +1,150 @@
+// Application processors start in real mode at `ap_trampoline_start`, after it has been
+// copied to a page below 1MiB. From there it goes straight to long mode, using the
+// kernel page table, and jumps to the kernel with the stack and argument from the data
+// area at the end. The trampoline page itself has to be identity mapped, and CR3 is
+// loaded before long mode, so the kernel page table is kept below 4GiB.
+global_asm!(r#"
+    .section .text.trampoline, "ax"
+    .global ap_trampoline_start
+    .global ap_trampoline_end
+    .global ap_trampoline_gdtr
+    .global ap_trampoline_far_jump
+    .global ap_trampoline_cr3
+    .global ap_trampoline_stack
+    .global ap_trampoline_entry
+    .global ap_trampoline_arg
+
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    movw %cs, %ax
+    movw %ax, %ds
+
+    // PAE and PGE
+    movl %cr4, %eax
+    orl $((1 << 5) | (1 << 7)), %eax
+    movl %eax, %cr4
+
+    movl ap_trampoline_cr3 - ap_trampoline_start, %eax
+    movl %eax, %cr3
+
+    // EFER.LME and EFER.NXE
+    movl $0xC0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    lgdtl ap_trampoline_gdtr - ap_trampoline_start
+
+    // Enabling paging and protection at once takes us to compatibility mode,
+    // the far jump into the 64-bit code segment does the rest.
+    movl %cr0, %eax
+    orl $0x80000001, %eax
+    movl %eax, %cr0
+
+    ljmpl *(ap_trampoline_far_jump - ap_trampoline_start)
+
+    .code64
+ap_trampoline_long_mode:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    xorw %ax, %ax
+    movw %ax, %fs
+    movw %ax, %gs
+
+    movq ap_trampoline_stack(%rip), %rsp
+    movq ap_trampoline_arg(%rip), %rdi
+    movq ap_trampoline_entry(%rip), %rax
+    pushq $0
+    jmpq *%rax
+
+    .p2align 3
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+ap_trampoline_gdtr:
+    .word 23
+    .long ap_trampoline_gdt - ap_trampoline_start
+ap_trampoline_far_jump:
+    .long ap_trampoline_long_mode - ap_trampoline_start
+    .word 0x08
+
+    .p2align 3
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_arg:
+    .quad 0
+ap_trampoline_end:
+"#, options(att_syntax));
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_gdtr: u8;
+    static ap_trampoline_far_jump: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_arg: u8;
+}
+
+/// Offset of a trampoline symbol from the start of the trampoline.
+fn offset(symbol: &u8) -> usize {
+    symbol as *const u8 as usize - unsafe { &ap_trampoline_start as *const u8 as usize }
+}
+
+/// A copy of the trampoline at physical address `base`, reachable at `virt`.
+pub struct Trampoline {
+    base: u64,
+    virt: *mut u8,
+}
+
+impl Trampoline {
+    /// Copy the trampoline to `virt`, which maps physical address `base`.
+    pub unsafe fn install(base: u64, virt: *mut u8, cr3: u32) -> Self {
+        assert!(base % 0x1000 == 0 && base < 0x10_0000, "Trampoline must be page aligned below 1MiB");
+
+        let len = offset(&ap_trampoline_end);
+        core::ptr::copy_nonoverlapping(&ap_trampoline_start as *const u8, virt, len);
+
+        let trampoline = Trampoline { base, virt };
+
+        // Turn the offsets in the GDT pointer and far jump into physical addresses.
+        trampoline.relocate(offset(&ap_trampoline_gdtr) + 2);
+        trampoline.relocate(offset(&ap_trampoline_far_jump));
+        trampoline.write(offset(&ap_trampoline_cr3), cr3 as u64);
+
+        trampoline
+    }
+
+    unsafe fn relocate(&self, offset: usize) {
+        let ptr = self.virt.add(offset) as *mut u32;
+        ptr.write_unaligned(ptr.read_unaligned() + self.base as u32);
+    }
+
+    unsafe fn write(&self, offset: usize, value: u64) {
+        core::ptr::write_volatile(self.virt.add(offset) as *mut u64, value);
+    }
+
+    /// The vector to send with the startup IPI.
+    pub fn vector(&self) -> u8 {
+        (self.base >> 12) as u8
+    }
+
+    /// Set up the trampoline for the next processor.
+    pub fn prepare(&self, stack: u64, entry: u64, arg: u64) {
+        unsafe {
+            self.write(offset(&ap_trampoline_stack), stack);
+            self.write(offset(&ap_trampoline_entry), entry);
+            self.write(offset(&ap_trampoline_arg), arg);
+        }
+    }
+}
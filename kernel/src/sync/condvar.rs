@@ -0,0 +1,47 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{MutexGuard, WaitQueue};
+
+/// Lets threads wait for a condition on data behind a `Mutex` to change.
+/// Like any condition variable, waiting can return without a notify, so check the condition in a loop.
+pub struct Condvar {
+    // Bumped by every notify, so a waiter can tell one happened since it unlocked.
+    generation: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            generation: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock `guard` and block until notified, then lock it again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        // Read before unlocking, any notify after the unlock changes it.
+        let generation = self.generation.load(Ordering::Acquire);
+        let mutex = MutexGuard::unlock(guard);
+
+        self.waiters.wait_until(|| {
+            if self.generation.load(Ordering::Acquire) != generation {
+                Some(())
+            } else {
+                None
+            }
+        });
+
+        mutex.lock()
+    }
+
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.notify_all();
+    }
+}
@@ -0,0 +1,66 @@
+use core::ops::{Deref, DerefMut};
+use spinning_top::{Spinlock, SpinlockGuard, const_spinlock};
+
+use super::{InterruptGuard, relax};
+
+/// A spinlock that keeps interrupts disabled while held.
+pub struct Locked<T> {
+    inner: Spinlock<T>,
+}
+
+impl<T> Locked<T> {
+    pub const fn new(inner: T) -> Self {
+        Locked {
+            inner: const_spinlock(inner),
+        }
+    }
+
+    pub fn lock(&self) -> LockedGuard<'_, T> {
+        let interrupts = InterruptGuard::new();
+
+        loop {
+            if let Some(guard) = self.inner.try_lock() {
+                return LockedGuard {
+                    guard,
+                    _interrupts: interrupts,
+                };
+            }
+
+            relax();
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<LockedGuard<'_, T>> {
+        let interrupts = InterruptGuard::new();
+
+        self.inner.try_lock().map(|guard| LockedGuard {
+            guard,
+            _interrupts: interrupts,
+        })
+    }
+
+    /// Only OK if whoever held the lock is never going to run again.
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+    }
+}
+
+pub struct LockedGuard<'a, T> {
+    guard: SpinlockGuard<'a, T>,
+    // Dropped after `guard`, so interrupts stay off until the lock is released.
+    _interrupts: InterruptGuard,
+}
+
+impl<T> Deref for LockedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for LockedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
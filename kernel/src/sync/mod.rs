@@ -0,0 +1,58 @@
+//! Locks, and other ways for threads to wait on each other.
+//!
+//! The spinning locks disable interrupts while held, so an interrupt handler can never spin on
+//! a lock its own CPU holds. The blocking ones put the thread to sleep instead, they can't be
+//! taken in interrupt handlers or before the scheduler runs, but can be released anywhere.
+
+use x86_64::instructions::interrupts;
+
+use crate::memory::tlb;
+
+pub use self::{
+    condvar::Condvar,
+    locked::{Locked, LockedGuard},
+    mutex::{Mutex, MutexGuard},
+    rwlock::RwLock,
+    semaphore::Semaphore,
+    ticket::{TicketLock, TicketLockGuard},
+    wait_queue::WaitQueue,
+};
+
+mod condvar;
+mod locked;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod ticket;
+mod wait_queue;
+
+/// Keeps interrupts disabled until dropped, then puts them back the way they were.
+struct InterruptGuard {
+    enabled: bool,
+}
+
+impl InterruptGuard {
+    fn new() -> Self {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        InterruptGuard {
+            enabled,
+        }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Wait a bit before checking again, in any loop that might spin with interrupts disabled.
+pub fn relax() {
+    // Whoever holds the lock might be waiting for us to flush, and we can't take the interrupt.
+    tlb::service();
+    core::hint::spin_loop();
+}
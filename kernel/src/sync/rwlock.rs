@@ -0,0 +1,119 @@
+use core::{cell::UnsafeCell, ops::{Deref, DerefMut}};
+use lockstate::RwLockState;
+
+use super::{InterruptGuard, relax};
+
+/// A spinlock for either any number of readers or a single writer, with interrupts disabled while held.
+/// A waiting writer keeps new readers out, so they can't starve it.
+pub struct RwLock<T> {
+    state: RwLockState,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: RwLockState::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let interrupts = InterruptGuard::new();
+
+        while !self.state.try_acquire_read() {
+            relax();
+        }
+
+        RwLockReadGuard {
+            lock: self,
+            _interrupts: interrupts,
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let interrupts = InterruptGuard::new();
+
+        while !self.state.try_acquire_write_or_wait() {
+            relax();
+        }
+
+        RwLockWriteGuard {
+            lock: self,
+            _interrupts: interrupts,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let interrupts = InterruptGuard::new();
+
+        if self.state.try_acquire_read() {
+            Some(RwLockReadGuard {
+                lock: self,
+                _interrupts: interrupts,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let interrupts = InterruptGuard::new();
+
+        if self.state.try_acquire_write() {
+            Some(RwLockWriteGuard {
+                lock: self,
+                _interrupts: interrupts,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _interrupts: InterruptGuard,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.release_read();
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _interrupts: InterruptGuard,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.release_write();
+    }
+}
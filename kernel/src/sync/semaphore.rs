@@ -0,0 +1,47 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::WaitQueue;
+
+/// A count of permits, blocking the thread while there are none left.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Take a permit, waiting for one if there are none.
+    pub fn acquire(&self) {
+        if !self.try_acquire() {
+            self.waiters.wait_until(|| if self.try_acquire() { Some(()) } else { None });
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.load(Ordering::Relaxed);
+        while permits > 0 {
+            match self.permits.compare_exchange(permits, permits - 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(current) => permits = current,
+            }
+        }
+
+        false
+    }
+
+    /// Give back a permit. Fine to call from interrupt handlers.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,74 @@
+use core::{cell::UnsafeCell, ops::{Deref, DerefMut}};
+use lockstate::TicketLockState;
+
+use super::{InterruptGuard, relax};
+
+/// A spinlock that is handed out in the order it was asked for, with interrupts disabled while held.
+pub struct TicketLock<T> {
+    tickets: TicketLockState,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for TicketLock<T> {}
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    pub const fn new(value: T) -> Self {
+        TicketLock {
+            tickets: TicketLockState::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        let interrupts = InterruptGuard::new();
+
+        let ticket = self.tickets.take_ticket();
+        while !self.tickets.is_serving(ticket) {
+            relax();
+        }
+
+        TicketLockGuard {
+            lock: self,
+            _interrupts: interrupts,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        let interrupts = InterruptGuard::new();
+
+        if !self.tickets.try_take_ticket() {
+            return None;
+        }
+
+        Some(TicketLockGuard {
+            lock: self,
+            _interrupts: interrupts,
+        })
+    }
+}
+
+pub struct TicketLockGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+    _interrupts: InterruptGuard,
+}
+
+impl<T> Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.tickets.release();
+    }
+}
@@ -0,0 +1,62 @@
+use alloc::{sync::Arc, vec::Vec};
+use x86_64::instructions::interrupts;
+
+use crate::thread::{self, Thread};
+
+use super::Locked;
+
+/// Threads blocked until something they wait for changes.
+pub struct WaitQueue {
+    // Oldest first.
+    threads: Locked<Vec<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            threads: Locked::new(Vec::new()),
+        }
+    }
+
+    /// Block until `condition` returns something, and return that.
+    /// `condition` runs with the queue locked, so changing what it looks at and then calling
+    /// `notify_one` or `notify_all` can't slip in between it failing and us blocking.
+    pub fn wait_until<R>(&self, mut condition: impl FnMut() -> Option<R>) -> R {
+        assert!(!crate::interrupts::in_interrupt(), "Blocking in an interrupt handler");
+
+        interrupts::without_interrupts(|| loop {
+            let mut threads = self.threads.lock();
+            if let Some(result) = condition() {
+                return result;
+            }
+
+            thread::block(move |current| threads.push(current));
+        })
+    }
+
+    /// Wake the thread that has waited the longest, if any. Returns whether there was one.
+    pub fn notify_one(&self) -> bool {
+        let thread = {
+            let mut threads = self.threads.lock();
+            if threads.is_empty() {
+                return false;
+            }
+            threads.remove(0)
+        };
+
+        thread::unblock(thread);
+        true
+    }
+
+    /// Wake every waiting thread, and return how many there were.
+    pub fn notify_all(&self) -> usize {
+        let threads = core::mem::take(&mut *self.threads.lock());
+        let count = threads.len();
+
+        for thread in threads {
+            thread::unblock(thread);
+        }
+
+        count
+    }
+}
@@ -0,0 +1,199 @@
+//! Kernel threads, scheduled round robin on a run queue per CPU.
+//! Threads stay on the CPU they were spawned on, and get preempted by the local APIC timer.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{cell::UnsafeCell, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use x86_64::instructions::interrupts;
+
+use crate::{memory::stack::KernelStack, sync::Locked, time::Instant};
+
+pub use self::scheduler::{init, init_cpu, preempt};
+
+mod scheduler;
+mod switch;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Ready,
+    Running,
+    Blocked,
+    Dead,
+}
+
+struct Waiters {
+    finished: bool,
+    threads: Vec<Arc<Thread>>,
+}
+
+pub struct Thread {
+    id: u64,
+    cpu: usize,
+    // Only changed with the run queue of `cpu` locked.
+    state: Locked<State>,
+    // The saved stack pointer, while the thread isn't running.
+    context: UnsafeCell<u64>,
+    // None for threads that started out as a CPU's boot code.
+    stack: Locked<Option<KernelStack>>,
+    entry: Locked<Option<Box<dyn FnOnce() + Send>>>,
+    waiters: Locked<Waiters>,
+}
+
+// `context` is only touched by the scheduler of `cpu`, with interrupts disabled.
+unsafe impl Sync for Thread {}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl Thread {
+    fn with_stack(cpu: usize, stack: Option<KernelStack>, entry: Option<Box<dyn FnOnce() + Send>>) -> Arc<Thread> {
+        Arc::new(Thread {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            cpu,
+            state: Locked::new(State::Running),
+            context: UnsafeCell::new(0),
+            stack: Locked::new(stack),
+            entry: Locked::new(entry),
+            waiters: Locked::new(Waiters {
+                finished: false,
+                threads: Vec::new(),
+            }),
+        })
+    }
+
+    /// A thread for the code that is already running on `cpu`.
+    fn bootstrap(cpu: usize) -> Arc<Thread> {
+        Thread::with_stack(cpu, None, None)
+    }
+
+    /// A thread that will run `entry` on `cpu` once it is scheduled.
+    fn new(cpu: usize, entry: Box<dyn FnOnce() + Send>) -> Arc<Thread> {
+        let stack = KernelStack::new().expect("Could not allocate thread stack");
+
+        // Make it look like `thread_switch` left the thread right before returning into `thread_start`.
+        let top = stack.top().as_mut_ptr::<u64>();
+        let context = unsafe {
+            let return_address = top.sub(1);
+            return_address.write(switch::start_address());
+
+            let registers = return_address.sub(switch::SAVED_REGISTERS);
+            core::ptr::write_bytes(registers, 0, switch::SAVED_REGISTERS);
+            registers as u64
+        };
+
+        let thread = Thread::with_stack(cpu, Some(stack), Some(entry));
+        unsafe {
+            *thread.context.get() = context;
+        }
+
+        thread
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+}
+
+#[no_mangle]
+extern "C" fn thread_entry() -> ! {
+    // We get here from `schedule`, with interrupts disabled.
+    let entry = current().entry.lock().take().expect("Thread started twice");
+    interrupts::enable();
+
+    entry();
+
+    exit();
+}
+
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<Locked<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    /// Wait for the thread to finish, and return what it returned.
+    pub fn join(self) -> T {
+        interrupts::without_interrupts(|| loop {
+            let mut waiters = self.thread.waiters.lock();
+            if waiters.finished {
+                break;
+            }
+
+            block(move |current| waiters.threads.push(current));
+        });
+
+        self.result.lock().take().expect("Thread finished without a result")
+    }
+}
+
+/// Start a new thread running `f`, on whichever CPU is next in line.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let result = Arc::new(Locked::new(None));
+    let packet = result.clone();
+
+    let thread = Thread::new(scheduler::pick_cpu(), Box::new(move || {
+        *packet.lock() = Some(f());
+    }));
+    scheduler::enqueue(thread.clone());
+
+    JoinHandle {
+        thread,
+        result,
+    }
+}
+
+pub fn current() -> Arc<Thread> {
+    scheduler::current()
+}
+
+/// Let other threads on this CPU run.
+pub fn yield_now() {
+    interrupts::without_interrupts(scheduler::schedule);
+}
+
+/// Block the current thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    scheduler::sleep(Instant::now() + duration);
+}
+
+/// Block the current thread until it is passed to `unblock`.
+/// `enlist` gets the thread, to put it wherever its waker will look. Interrupts must be disabled,
+/// so an `unblock` right after `enlist` still counts.
+pub fn block(enlist: impl FnOnce(Arc<Thread>)) {
+    let current = current();
+    scheduler::block(&current);
+    enlist(current);
+
+    scheduler::schedule();
+}
+
+/// Make a thread that `block`ed ready again. Fine to call from interrupt handlers.
+pub fn unblock(thread: Arc<Thread>) {
+    interrupts::without_interrupts(|| scheduler::wake(thread));
+}
+
+/// Stop the current thread, waking up anyone joining it.
+pub fn exit() -> ! {
+    interrupts::disable();
+
+    let current = current();
+    {
+        let mut waiters = current.waiters.lock();
+        waiters.finished = true;
+        for waiter in waiters.threads.drain(..) {
+            scheduler::wake(waiter);
+        }
+    }
+
+    scheduler::exit(current)
+}
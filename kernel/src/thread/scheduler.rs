@@ -0,0 +1,318 @@
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{cell::{Cell, RefCell}, sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering}, time::Duration};
+use x86_64::instructions::interrupts;
+
+use crate::{apic::local, cpu::{self, Feature}, interrupts::{InterruptFrame, RESCHEDULE_VECTOR, TIMER_VECTOR, register_handler}, percpu, smp::{self, MAX_CPUS}, sync::{Locked, LockedGuard}, time::{self, Instant}};
+
+use super::{State, Thread, switch::thread_switch};
+
+/// Length of a time slice.
+pub const TICK_MS: u64 = 10;
+
+const TIME_SLICE: Duration = Duration::from_millis(TICK_MS);
+
+struct RunQueue {
+    ready: VecDeque<Arc<Thread>>,
+    sleeping: Vec<(Instant, Arc<Thread>)>,
+    // Threads that exited, their stacks are freed by the idle thread.
+    dead: Vec<Arc<Thread>>,
+    // Runs when nothing else is ready, never part of `ready`.
+    idle: Arc<Thread>,
+}
+
+impl RunQueue {
+    /// When the timer has to go off next, if `next` is what runs until then.
+    /// Idle CPUs only wake up for sleepers, everything else gets preempted after a time slice.
+    fn next_deadline(&self, next: &Arc<Thread>) -> Option<Instant> {
+        let sleeper = self.sleeping.iter().map(|&(deadline, _)| deadline).min();
+        if Arc::ptr_eq(next, &self.idle) {
+            return sleeper;
+        }
+
+        let slice = Instant::now() + TIME_SLICE;
+        Some(sleeper.map_or(slice, |sleeper| sleeper.min(slice)))
+    }
+}
+
+// Every CPU has its own queue, but other CPUs put threads on it when they spawn or wake them.
+// Only used to fill the array, every element is its own lock.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_QUEUE: Locked<Option<RunQueue>> = Locked::new(None);
+static QUEUES: [Locked<Option<RunQueue>>; MAX_CPUS] = [NO_QUEUE; MAX_CPUS];
+
+static TIMER_TICKS: AtomicU32 = AtomicU32::new(0);
+
+/// Idle CPUs with MWAIT watch their line in here instead of waiting for an IPI.
+#[repr(align(64))]
+struct WakeLine(AtomicU64);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WAKE: WakeLine = WakeLine(AtomicU64::new(0));
+static WAKE: [WakeLine; MAX_CPUS] = [NO_WAKE; MAX_CPUS];
+static NEXT_CPU: AtomicUsize = AtomicUsize::new(0);
+
+percpu! {
+    static CURRENT: RefCell<Option<Arc<Thread>>> = RefCell::new(None);
+    static NEED_RESCHED: Cell<bool> = Cell::new(false);
+}
+
+fn queue(cpu: usize) -> LockedGuard<'static, Option<RunQueue>> {
+    QUEUES[cpu].lock()
+}
+
+/// Run `f` on the queue of `cpu`.
+fn with_queue<R>(cpu: usize, f: impl FnOnce(&mut RunQueue) -> R) -> R {
+    f(queue(cpu).as_mut().expect("CPU has no scheduler"))
+}
+
+/// Get the idle thread of `cpu` to look at its queue again.
+fn kick(cpu: usize) {
+    if cpu::has(Feature::Monitor) {
+        WAKE[cpu].0.fetch_add(1, Ordering::Release);
+    } else {
+        unsafe {
+            local::send_ipi(smp::cpu(cpu).apic_id, RESCHEDULE_VECTOR as u32);
+        }
+    }
+}
+
+/// Whether `cpu` is another CPU, that is idle and needs a `kick` to notice new work.
+/// Called with the queue of `cpu` locked.
+fn needs_kick(queue: &RunQueue, cpu: usize) -> bool {
+    cpu != smp::current().index && *queue.idle.state.lock() == State::Running
+}
+
+/// Program the timer of this CPU, or stop it if there is nothing to wait for.
+fn set_timer(deadline: Option<Instant>) {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return local::stop_timer(),
+    };
+
+    match time::tsc_at(deadline) {
+        Some(tsc) if cpu::has(Feature::TscDeadline) => local::start_tsc_deadline_timer(TIMER_VECTOR, tsc),
+        _ => {
+            let nanos = deadline.duration_since(Instant::now()).as_nanos();
+            let ticks = nanos * TIMER_TICKS.load(Ordering::Relaxed) as u128 / TIME_SLICE.as_nanos();
+            local::start_oneshot_timer(TIMER_VECTOR, ticks.min(u32::MAX as u128) as u32);
+        },
+    }
+}
+
+pub fn current() -> Arc<Thread> {
+    CURRENT.with(|current| current.borrow().clone()).expect("No current thread")
+}
+
+/// Pick a CPU with a scheduler for a new thread, round robin.
+pub fn pick_cpu() -> usize {
+    loop {
+        let cpu = NEXT_CPU.fetch_add(1, Ordering::Relaxed) % MAX_CPUS;
+        if interrupts::without_interrupts(|| queue(cpu).is_some()) {
+            return cpu;
+        }
+    }
+}
+
+pub fn enqueue(thread: Arc<Thread>) {
+    interrupts::without_interrupts(|| {
+        let kick_cpu = with_queue(thread.cpu, |queue| {
+            *thread.state.lock() = State::Ready;
+            queue.ready.push_back(thread.clone());
+            needs_kick(queue, thread.cpu)
+        });
+
+        if kick_cpu {
+            kick(thread.cpu);
+        }
+    });
+}
+
+/// Make a blocked thread ready again. Interrupts must be disabled.
+pub fn wake(thread: Arc<Thread>) {
+    let cpu = thread.cpu;
+    let kick_cpu = with_queue(cpu, |queue| {
+        let mut state = thread.state.lock();
+        if *state != State::Blocked {
+            return false;
+        }
+
+        *state = State::Ready;
+        drop(state);
+        queue.ready.push_back(thread);
+        needs_kick(queue, cpu)
+    });
+
+    if kick_cpu {
+        kick(cpu);
+    }
+}
+
+/// Mark the current thread as blocked, it won't run again until someone calls `wake`.
+/// Interrupts must be disabled until the following `schedule`.
+pub fn block(thread: &Arc<Thread>) {
+    with_queue(thread.cpu, |_| {
+        *thread.state.lock() = State::Blocked;
+    });
+}
+
+/// Block the current thread until `deadline`.
+pub fn sleep(deadline: Instant) {
+    interrupts::without_interrupts(|| {
+        let current = current();
+        with_queue(current.cpu, |queue| {
+            *current.state.lock() = State::Blocked;
+            queue.sleeping.push((deadline, current.clone()));
+        });
+        drop(current);
+
+        schedule();
+    });
+}
+
+/// Switch away from the current thread for good.
+pub fn exit(thread: Arc<Thread>) -> ! {
+    interrupts::disable();
+
+    with_queue(thread.cpu, |queue| {
+        *thread.state.lock() = State::Dead;
+        queue.dead.push(thread);
+    });
+
+    schedule();
+    unreachable!("Dead thread was scheduled");
+}
+
+/// Switch to the next ready thread of this CPU, if there is one.
+/// The current thread goes to the back of the queue if it is still runnable.
+/// Interrupts must be disabled.
+pub fn schedule() {
+    let current = current();
+
+    let next = with_queue(current.cpu, |queue| {
+        {
+            let mut state = current.state.lock();
+            if *state == State::Running {
+                *state = State::Ready;
+                if !Arc::ptr_eq(&current, &queue.idle) {
+                    queue.ready.push_back(current.clone());
+                }
+            }
+        }
+
+        let next = queue.ready.pop_front().unwrap_or_else(|| queue.idle.clone());
+        *next.state.lock() = State::Running;
+        set_timer(queue.next_deadline(&next));
+        next
+    });
+
+    if Arc::ptr_eq(&current, &next) {
+        return;
+    }
+
+    // Whoever put `current` to sleep holds on to it, so the pointer stays valid.
+    let from = current.context.get();
+    let to = unsafe { *next.context.get() };
+    drop(current);
+    CURRENT.with(|current| *current.borrow_mut() = Some(next));
+
+    unsafe {
+        thread_switch(from, to);
+    }
+}
+
+/// Switch threads if the timer asked for it. Called on the way out of an interrupt.
+pub fn preempt() {
+    if NEED_RESCHED.with(|need_resched| need_resched.replace(false)) {
+        schedule();
+    }
+}
+
+fn timer(_frame: &mut InterruptFrame) {
+    with_queue(smp::current().index, |queue| {
+        let now = Instant::now();
+        let mut i = 0;
+        while i < queue.sleeping.len() {
+            if queue.sleeping[i].0 <= now {
+                let (_, thread) = queue.sleeping.swap_remove(i);
+                *thread.state.lock() = State::Ready;
+                queue.ready.push_back(thread);
+            } else {
+                i += 1;
+            }
+        }
+    });
+
+    NEED_RESCHED.with(|need_resched| need_resched.set(true));
+}
+
+fn reschedule(_frame: &mut InterruptFrame) {
+    NEED_RESCHED.with(|need_resched| need_resched.set(true));
+}
+
+/// Free the stacks of threads that exited on this CPU.
+fn reap() {
+    let cpu = smp::current().index;
+    let dead = interrupts::without_interrupts(|| with_queue(cpu, |queue| core::mem::take(&mut queue.dead)));
+
+    for thread in dead {
+        thread.stack.lock().take();
+    }
+}
+
+/// Sleep until there is something to run, with the timer only set for the next sleeper.
+fn idle() {
+    let cpu = smp::current().index;
+    let mwait = cpu::has(Feature::Monitor);
+
+    loop {
+        reap();
+
+        interrupts::disable();
+        if mwait {
+            // Watch before looking at the queue, so a kick right after still wakes us.
+            unsafe {
+                cpu::monitor(&WAKE[cpu] as *const WakeLine as *const u8);
+            }
+        }
+
+        if with_queue(cpu, |queue| !queue.ready.is_empty()) {
+            schedule();
+        } else if mwait {
+            unsafe {
+                cpu::enable_and_mwait();
+            }
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// Measure the local APIC timer and start scheduling on the bootstrap processor.
+pub fn init() {
+    let ticks = time::calibrate_apic_timer() * TICK_MS / 1000;
+    TIMER_TICKS.store(ticks.min(u32::MAX as u64) as u32, Ordering::Relaxed);
+
+    register_handler(TIMER_VECTOR, timer);
+    register_handler(RESCHEDULE_VECTOR, reschedule);
+
+    init_cpu();
+}
+
+/// Turn whatever is running into the first thread of this CPU and start its first time slice.
+pub fn init_cpu() {
+    let cpu = smp::current().index;
+    let current = Thread::bootstrap(cpu);
+    let idle = Thread::new(cpu, alloc::boxed::Box::new(idle));
+
+    interrupts::without_interrupts(|| {
+        *queue(cpu) = Some(RunQueue {
+            ready: VecDeque::new(),
+            sleeping: Vec::new(),
+            dead: Vec::new(),
+            idle,
+        });
+        CURRENT.with(|slot| *slot.borrow_mut() = Some(current));
+    });
+
+    interrupts::without_interrupts(|| set_timer(Some(Instant::now() + TIME_SLICE)));
+}
@@ -0,0 +1,45 @@
+// `thread_switch(from, to)` saves the callee-saved registers on the current stack,
+// stores the stack pointer in `*from` and continues on the stack `to` points at.
+// Everything else is saved by the caller, as usual for a function call.
+//
+// A new thread starts with a stack that looks like `thread_switch` left it, with
+// `thread_start` as the return address.
+global_asm!(r#"
+    .section .text
+    .global thread_switch
+thread_switch:
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+
+    movq %rsp, (%rdi)
+    movq %rsi, %rsp
+
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %rbx
+    popq %rbp
+    ret
+
+    .global thread_start
+thread_start:
+    call thread_entry
+    ud2
+"#, options(att_syntax));
+
+/// The callee-saved registers pushed by `thread_switch`.
+pub const SAVED_REGISTERS: usize = 6;
+
+extern "C" {
+    pub fn thread_switch(from: *mut u64, to: u64);
+    pub static thread_start: u8;
+}
+
+pub fn start_address() -> u64 {
+    unsafe { &thread_start as *const u8 as u64 }
+}
@@ -0,0 +1,93 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+use crate::{acpi::{AddressSpace, hpet::Hpet}, memory::{CacheMode, mmio}, sync::relax};
+
+// Register offsets
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+
+const REGISTERS_SIZE: u64 = 0x400;
+
+const ENABLE: u64 = 1 << 0;
+
+/// The spec doesn't allow for a slower counter than this, in femtoseconds per tick.
+const MAX_PERIOD: u64 = 100_000_000;
+
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+static BASE: AtomicU64 = AtomicU64::new(0);
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+static COUNTER_64BIT: AtomicBool = AtomicBool::new(false);
+
+unsafe fn read(reg: u64) -> u64 {
+    core::ptr::read_volatile((BASE.load(Ordering::Relaxed) + reg) as *const u64)
+}
+
+unsafe fn write(reg: u64, value: u64) {
+    core::ptr::write_volatile((BASE.load(Ordering::Relaxed) + reg) as *mut u64, value);
+}
+
+pub fn is_present() -> bool {
+    PERIOD.load(Ordering::Relaxed) != 0
+}
+
+/// Whether the counter is good as a clock, a 32-bit one wraps within minutes.
+pub fn is_64bit() -> bool {
+    COUNTER_64BIT.load(Ordering::Relaxed)
+}
+
+pub fn counter() -> u64 {
+    unsafe { read(MAIN_COUNTER) }
+}
+
+/// Counter ticks to nanoseconds.
+pub fn nanos(ticks: u64) -> u64 {
+    (ticks as u128 * PERIOD.load(Ordering::Relaxed) as u128 / FEMTOS_PER_NANO) as u64
+}
+
+/// Busy wait for `ns` nanoseconds.
+pub fn delay_ns(ns: u64) {
+    let mask = if is_64bit() { u64::MAX } else { u32::MAX as u64 };
+    let ticks = (ns as u128 * FEMTOS_PER_NANO / PERIOD.load(Ordering::Relaxed) as u128) as u64;
+
+    let start = counter();
+    while counter().wrapping_sub(start) & mask < ticks {
+        relax();
+    }
+}
+
+/// Find the HPET through ACPI and start its main counter.
+pub fn init() -> bool {
+    let table = match Hpet::get() {
+        Some(table) => table,
+        None => return false,
+    };
+
+    let address = table.base_address();
+    if address.address_space != AddressSpace::SystemMemory {
+        return false;
+    }
+
+    let registers = match mmio::map(PhysAddr::new(address.address), REGISTERS_SIZE, CacheMode::Uncached) {
+        Ok(registers) => registers,
+        Err(_) => return false,
+    };
+
+    // Check it's really there before keeping the mapping, dropping it unmaps the registers again.
+    let capabilities = unsafe { core::ptr::read_volatile((registers.addr() + GENERAL_CAPABILITIES).as_ptr::<u64>()) };
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD {
+        return false;
+    }
+    BASE.store(registers.leak().as_u64(), Ordering::Relaxed);
+
+    unsafe {
+        write(GENERAL_CONFIGURATION, read(GENERAL_CONFIGURATION) | ENABLE);
+    }
+
+    COUNTER_64BIT.store(table.counter_is_64bit(), Ordering::Relaxed);
+    PERIOD.store(period, Ordering::Relaxed);
+    true
+}
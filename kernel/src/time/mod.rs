@@ -0,0 +1,214 @@
+//! Monotonic time since boot.
+//!
+//! The HPET, or the PIT when ACPI doesn't describe one, is the reference everything else is
+//! measured against. With an invariant TSC the clock is the TSC, calibrated against that reference.
+//! Otherwise the HPET counter is read directly, and only without both we fall back to a TSC that may drift.
+//!
+//! Timer deadlines use the TSC deadline timer when the clock is an invariant TSC, and otherwise the
+//! local APIC timer, calibrated against the reference. The APIC timer can't be the clock itself,
+//! it is a 32-bit countdown that only the CPU it belongs to can read.
+//!
+//! Wall-clock time is the CMOS RTC as read at boot, carried forward by the monotonic clock.
+
+use core::{convert::TryFrom, ops::{Add, Sub}, sync::atomic::{AtomicU64, AtomicU8, Ordering}};
+
+use crate::{apic::local, console, cpu::{self, Feature}, println, sync::relax, thread};
+
+pub use core::time::Duration;
+pub use datetime::DateTime;
+
+mod hpet;
+mod pit;
+pub mod rtc;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// How long to measure the TSC and the local APIC timer for.
+const CALIBRATION_TIME: Duration = Duration::from_millis(50);
+
+// What `Instant::now` reads.
+const CLOCK_NONE: u8 = 0;
+const CLOCK_TSC: u8 = 1;
+const CLOCK_HPET: u8 = 2;
+
+static CLOCK: AtomicU8 = AtomicU8::new(CLOCK_NONE);
+// Where the clock started counting, in its own ticks.
+static CLOCK_BASE: AtomicU64 = AtomicU64::new(0);
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+// The Unix time of the zero instant, in nanoseconds.
+static UNIX_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// A point in time since the clock started, never going backwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    /// Before `init` this is always the zero instant.
+    pub fn now() -> Instant {
+        Instant {
+            nanos: now_nanos(),
+        }
+    }
+
+    /// Time since boot, or rather since `init`.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+
+    /// How long after `earlier` this is, zero if `earlier` is actually later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant {
+            nanos: self.nanos.saturating_add(saturating_nanos(duration)),
+        }
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// `duration` in nanoseconds, which only overflows after centuries.
+fn saturating_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+fn now_nanos() -> u64 {
+    let base = CLOCK_BASE.load(Ordering::Relaxed);
+
+    match CLOCK.load(Ordering::Relaxed) {
+        CLOCK_TSC => {
+            let ticks = rdtsc().saturating_sub(base);
+            (ticks as u128 * NANOS_PER_SEC / TSC_FREQUENCY.load(Ordering::Relaxed) as u128) as u64
+        },
+        CLOCK_HPET => hpet::nanos(hpet::counter().saturating_sub(base)),
+        _ => 0,
+    }
+}
+
+/// Busy wait on the HPET or PIT, this works before `init` picked a clock.
+fn reference_delay(duration: Duration) {
+    if hpet::is_present() {
+        hpet::delay_ns(saturating_nanos(duration));
+    } else {
+        pit::delay_us(u64::try_from(duration.as_micros()).unwrap_or(u64::MAX));
+    }
+}
+
+/// Busy wait for at least `duration`, without needing interrupts.
+pub fn delay(duration: Duration) {
+    if CLOCK.load(Ordering::Relaxed) == CLOCK_NONE {
+        return reference_delay(duration);
+    }
+
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        relax();
+    }
+}
+
+/// Block the current thread for at least `duration`, letting other threads run.
+pub fn sleep(duration: Duration) {
+    thread::sleep(duration);
+}
+
+/// Nanoseconds since the Unix epoch, in UTC. Before `init` this is just the time since boot.
+fn unix_nanos() -> u64 {
+    UNIX_OFFSET.load(Ordering::Relaxed) + now_nanos()
+}
+
+/// Seconds since the Unix epoch, in UTC.
+pub fn unix_timestamp() -> u64 {
+    unix_nanos() / NANOS_PER_SEC as u64
+}
+
+/// The current UTC date and time.
+pub fn wall_clock() -> DateTime {
+    DateTime::from_unix(unix_timestamp())
+}
+
+/// What the TSC reads at `instant`, if the clock is an invariant TSC.
+pub fn tsc_at(instant: Instant) -> Option<u64> {
+    if CLOCK.load(Ordering::Relaxed) != CLOCK_TSC || !cpu::has(Feature::InvariantTsc) {
+        return None;
+    }
+
+    let ticks = instant.nanos as u128 * TSC_FREQUENCY.load(Ordering::Relaxed) as u128 / NANOS_PER_SEC;
+    Some(CLOCK_BASE.load(Ordering::Relaxed) + ticks as u64)
+}
+
+fn calibrate_tsc() -> u64 {
+    let start = rdtsc();
+    reference_delay(CALIBRATION_TIME);
+    let elapsed = rdtsc() - start;
+
+    (elapsed as u128 * NANOS_PER_SEC / CALIBRATION_TIME.as_nanos()) as u64
+}
+
+/// Measure how many ticks per second the local APIC timer of this CPU counts, against the
+/// reference rather than the clock, which might be a TSC that drifts. Needs the local APIC.
+pub fn calibrate_apic_timer() -> u64 {
+    let ticks = local::calibrate_timer(|| reference_delay(CALIBRATION_TIME));
+    let frequency = (ticks as u128 * NANOS_PER_SEC / CALIBRATION_TIME.as_nanos()) as u64;
+
+    let reference = if hpet::is_present() { "HPET" } else { "PIT" };
+    println!("Time: local APIC timer at {} MHz, calibrated against the {}", frequency / 1_000_000, reference);
+    frequency
+}
+
+/// Find a reference timer and start the clock. This needs ACPI for the HPET.
+pub fn init() {
+    let hpet = hpet::init();
+    let reference = if hpet { "HPET" } else { "PIT" };
+
+    if cpu::has(Feature::InvariantTsc) || !hpet::is_64bit() {
+        if !cpu::has(Feature::InvariantTsc) {
+            println!("Time: TSC is not invariant, the clock may drift and timers use the local APIC timer");
+        }
+
+        let frequency = calibrate_tsc();
+        TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+        CLOCK_BASE.store(rdtsc(), Ordering::Relaxed);
+        CLOCK.store(CLOCK_TSC, Ordering::Relaxed);
+
+        println!("Time: TSC at {} MHz, calibrated against the {}", frequency / 1_000_000, reference);
+    } else {
+        CLOCK_BASE.store(hpet::counter(), Ordering::Relaxed);
+        CLOCK.store(CLOCK_HPET, Ordering::Relaxed);
+
+        println!("Time: HPET counter");
+    }
+
+    // Without the RTC the wall clock starts at the epoch, and isn't worth printing.
+    let boot_time = match rtc::read_time() {
+        Some(boot_time) => boot_time,
+        None => return println!("Time: RTC is not responding, no wall-clock time"),
+    };
+    let offset = boot_time.to_unix().saturating_mul(NANOS_PER_SEC as u64).saturating_sub(now_nanos());
+    UNIX_OFFSET.store(offset, Ordering::Relaxed);
+
+    println!("Time: {} UTC", boot_time);
+    console::set_timestamps(true);
+}
@@ -0,0 +1,53 @@
+use x86_64::instructions::port::Port;
+use crate::sync::relax;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const CHANNEL2_GATE: u16 = 0x61;
+
+const GATE_ENABLE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const OUTPUT_HIGH: u8 = 1 << 5;
+
+// Channel 2 (0b10 << 6), lobyte/hibyte (0b11 << 4), mode 0 (interrupt on terminal count), binary
+const CHANNEL2_ONESHOT: u8 = 0b1011_0000;
+
+/// The longest delay a single countdown can cover.
+const MAX_TICKS: u64 = 0xFFFF;
+
+/// Busy wait for `us` microseconds, using channel 2 of the PIT.
+/// This does not need interrupts, so it is usable before anything else is set up.
+pub fn delay_us(us: u64) {
+    let mut ticks = us * PIT_FREQUENCY / 1_000_000;
+
+    while ticks > 0 {
+        let count = ticks.min(MAX_TICKS);
+        countdown(count as u16);
+        ticks -= count;
+    }
+}
+
+fn countdown(count: u16) {
+    let mut gate: Port<u8> = Port::new(CHANNEL2_GATE);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut data: Port<u8> = Port::new(CHANNEL2_DATA);
+
+    unsafe {
+        // Disable the speaker and stop the gate while we program the counter.
+        let value = gate.read() & !(SPEAKER_ENABLE | GATE_ENABLE);
+        gate.write(value);
+
+        command.write(CHANNEL2_ONESHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // Raising the gate starts the countdown.
+        gate.write(value | GATE_ENABLE);
+
+        while gate.read() & OUTPUT_HIGH == 0 {
+            relax();
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! The CMOS real-time clock, only read once at boot to find out what time it is.
+//! It is assumed to run in UTC, like QEMU's default `-rtc base=utc`.
+//! It can also raise a periodic interrupt, which nothing depends on but is easy to check for.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+use crate::{acpi::fadt::Fadt, apic::RouteError, interrupts::{self, InterruptFrame}, sync::{Locked, relax}};
+
+use super::DateTime;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE: u8 = 0x0F;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+
+const RTC_IRQ: u8 = 8;
+/// 32768Hz >> (rate - 1), so 1024Hz.
+const PERIODIC_RATE: u8 = 6;
+
+/// Set in the hours register for PM, in 12-hour mode.
+const HOURS_PM: u8 = 1 << 7;
+
+/// Without a century register, two digit years are taken to be in this century.
+const DEFAULT_CENTURY: u32 = 20;
+
+/// An update takes about 2ms, this is a lot longer than that even on a fast machine.
+const MAX_UPDATE_POLLS: u32 = 1_000_000;
+/// Reading the same values twice should take two tries, or three if an update got in between.
+const MAX_READS: u32 = 10;
+
+// Selecting a register and reading it has to happen without anyone else selecting another.
+static CMOS: Locked<()> = Locked::new(());
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn read(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(CMOS_INDEX);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+
+    index.write(register);
+    data.read()
+}
+
+unsafe fn write(register: u8, value: u8) {
+    let mut index: Port<u8> = Port::new(CMOS_INDEX);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+
+    index.write(register);
+    data.write(value);
+}
+
+/// The time registers as they are, in whatever format the RTC uses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Registers {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+/// Read the registers once no update is in progress, if that ever happens.
+unsafe fn read_registers(century: Option<u8>) -> Option<Registers> {
+    // The registers are garbage while the RTC updates them, once a second.
+    let mut polls = 0;
+    while read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        polls += 1;
+        if polls == MAX_UPDATE_POLLS {
+            return None;
+        }
+        relax();
+    }
+
+    Some(Registers {
+        seconds: read(REG_SECONDS),
+        minutes: read(REG_MINUTES),
+        hours: read(REG_HOURS),
+        day: read(REG_DAY),
+        month: read(REG_MONTH),
+        year: read(REG_YEAR),
+        century: century.map(|register| read(register)),
+    })
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Read the current date and time, none if the RTC doesn't settle on one.
+pub fn read_time() -> Option<DateTime> {
+    let century_register = Fadt::get().and_then(|fadt| fadt.rtc_century_register());
+
+    let _cmos = CMOS.lock();
+    let (registers, status_b) = unsafe {
+        // An update could still start right after we checked for it, so read until we
+        // get the same values twice.
+        let mut registers = read_registers(century_register)?;
+        let mut reads = 1;
+        loop {
+            let again = read_registers(century_register)?;
+            if again == registers {
+                break;
+            }
+
+            reads += 1;
+            if reads == MAX_READS {
+                return None;
+            }
+            registers = again;
+        }
+
+        (registers, read(REG_STATUS_B))
+    };
+
+    let decode = |value: u8| if status_b & STATUS_B_BINARY != 0 { value } else { from_bcd(value) };
+
+    let mut hour = decode(registers.hours & !HOURS_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon.
+        hour %= 12;
+        if registers.hours & HOURS_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    let century = registers.century.map_or(DEFAULT_CENTURY, |century| decode(century) as u32);
+
+    Some(DateTime {
+        year: century * 100 + decode(registers.year) as u32,
+        month: decode(registers.month),
+        day: decode(registers.day),
+        hour,
+        minute: decode(registers.minutes),
+        second: decode(registers.seconds),
+    })
+}
+
+fn periodic_interrupt(_frame: &mut InterruptFrame) {
+    let _cmos = CMOS.lock();
+    unsafe {
+        // Nothing else is raised until this one is acknowledged.
+        read(REG_STATUS_C);
+    }
+
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Start counting periodic interrupts, delivered to the current processor.
+pub fn start_periodic() -> Result<(), RouteError> {
+    interrupts::register_irq(RTC_IRQ, periodic_interrupt)?;
+
+    let _cmos = CMOS.lock();
+    unsafe {
+        write(REG_STATUS_A, (read(REG_STATUS_A) & !STATUS_A_RATE) | PERIODIC_RATE);
+        write(REG_STATUS_B, read(REG_STATUS_B) | STATUS_B_PERIODIC);
+        read(REG_STATUS_C);
+    }
+
+    Ok(())
+}
+
+pub fn stop_periodic() {
+    {
+        let _cmos = CMOS.lock();
+        unsafe {
+            write(REG_STATUS_B, read(REG_STATUS_B) & !STATUS_B_PERIODIC);
+            read(REG_STATUS_C);
+        }
+    }
+
+    interrupts::unregister_irq(RTC_IRQ);
+}
+
+/// How many periodic interrupts there have been so far.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
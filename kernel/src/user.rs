@@ -0,0 +1,143 @@
+//! Access to user memory from the kernel.
+//!
+//! With SMAP on, the kernel faults on user pages unless it explicitly allows it with `stac`,
+//! which `UserAccess` does for as short as possible. SMEP keeps the kernel from ever running
+//! user code, and UMIP keeps user mode from reading the descriptor table registers.
+//!
+//! The copies go through `user_copy`, and a page fault in there on a user address sends it to
+//! `user_copy_resume` instead of being fatal, so a bad user pointer is just an error.
+
+use core::{marker::PhantomData, sync::atomic::{AtomicBool, Ordering}};
+use x86_64::{VirtAddr, registers::control::{Cr4, Cr4Flags}};
+
+use crate::cpu::{self, Feature};
+
+/// User mode gets the lower half of the address space.
+const USER_TOP: u64 = 0x0000_8000_0000_0000;
+
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+// `user_copy(dst, src, len)` copies `len` bytes and returns how many it didn't get to.
+// `rep movsb` keeps RCX up to date as it goes, so after a fault it is the count left.
+global_asm!(r#"
+    .section .text
+    .global user_copy
+user_copy:
+    movq %rdx, %rcx
+    .global user_copy_fault
+user_copy_fault:
+    rep movsb
+    .global user_copy_resume
+user_copy_resume:
+    movq %rcx, %rax
+    ret
+"#, options(att_syntax));
+
+extern "C" {
+    fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    static user_copy_fault: u8;
+    static user_copy_resume: u8;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserAccessError {
+    BadAddress,
+}
+
+/// Lets the current CPU access user memory until dropped.
+pub struct UserAccess {
+    // `stac` only affects the CPU that ran it.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl UserAccess {
+    pub fn new() -> Self {
+        // Without SMAP these instructions don't exist, but then there is nothing to allow either.
+        if SMAP.load(Ordering::Relaxed) {
+            unsafe {
+                asm!("stac", options(nostack));
+            }
+        }
+
+        UserAccess {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if SMAP.load(Ordering::Relaxed) {
+            unsafe {
+                asm!("clac", options(nostack));
+            }
+        }
+    }
+}
+
+/// Check that `len` bytes at `addr` are all user addresses.
+fn check(addr: VirtAddr, len: usize) -> Result<(), UserAccessError> {
+    match addr.as_u64().checked_add(len as u64) {
+        Some(end) if end <= USER_TOP => Ok(()),
+        _ => Err(UserAccessError::BadAddress),
+    }
+}
+
+/// Copy `dst.len()` bytes from user memory at `src`.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), UserAccessError> {
+    check(src, dst.len())?;
+
+    let _access = UserAccess::new();
+    match unsafe { user_copy(dst.as_mut_ptr(), src.as_ptr(), dst.len()) } {
+        0 => Ok(()),
+        _ => Err(UserAccessError::BadAddress),
+    }
+}
+
+/// Copy `src` to user memory at `dst`.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), UserAccessError> {
+    check(dst, src.len())?;
+
+    let _access = UserAccess::new();
+    match unsafe { user_copy(dst.as_mut_ptr(), src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(UserAccessError::BadAddress),
+    }
+}
+
+/// Where a page fault at `rip` on `addr` should continue, if it is one of the copies running
+/// into a user page that isn't there or doesn't allow the access.
+pub fn fixup(rip: u64, addr: VirtAddr) -> Option<u64> {
+    let (fault, resume) = unsafe { (&user_copy_fault as *const u8 as u64, &user_copy_resume as *const u8 as u64) };
+
+    if rip == fault && addr.as_u64() < USER_TOP {
+        Some(resume)
+    } else {
+        None
+    }
+}
+
+/// Whether SMAP is on, so user memory is only reachable through `UserAccess`.
+pub fn smap() -> bool {
+    SMAP.load(Ordering::Relaxed)
+}
+
+/// Turn on whichever of SMEP, SMAP and UMIP the bootstrap processor supports.
+/// Application processors copy its CR4.
+pub fn init() {
+    let mut flags = Cr4Flags::empty();
+    if cpu::has(Feature::Smep) {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if cpu::has(Feature::Smap) {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    if cpu::has(Feature::Umip) {
+        flags |= Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION;
+    }
+
+    unsafe {
+        Cr4::update(|cr4| cr4.insert(flags));
+    }
+    SMAP.store(flags.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION), Ordering::Relaxed);
+}
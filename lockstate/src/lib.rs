@@ -0,0 +1,9 @@
+//! The atomic state behind the kernel's spinlocks, without the spinning or the interrupt handling,
+//! so it can be tested on the host.
+
+#![no_std]
+
+pub use self::{rwlock::RwLockState, ticket::TicketLockState};
+
+mod rwlock;
+mod ticket;
@@ -0,0 +1,118 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+// The rest of the state is the number of readers.
+const WRITER: u32 = 1 << 31;
+const WRITER_WAITING: u32 = 1 << 30;
+const READERS: u32 = WRITER_WAITING - 1;
+
+/// Either any number of readers or a single writer.
+/// A waiting writer keeps new readers out, so they can't starve it.
+pub struct RwLockState {
+    state: AtomicU32,
+}
+
+impl RwLockState {
+    pub const fn new() -> Self {
+        RwLockState {
+            state: AtomicU32::new(0),
+        }
+    }
+
+    pub fn try_acquire_read(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WRITER_WAITING) != 0 {
+            return false;
+        }
+
+        assert!(state & READERS != READERS, "Too many readers");
+        self.state.compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    pub fn try_acquire_write(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | READERS) != 0 {
+            return false;
+        }
+
+        // Clears `WRITER_WAITING`, other waiting writers set it again.
+        self.state.compare_exchange(state, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    /// Like `try_acquire_write`, but keeps new readers out on failure until a writer got in.
+    pub fn try_acquire_write_or_wait(&self) -> bool {
+        if self.try_acquire_write() {
+            return true;
+        }
+
+        if self.state.load(Ordering::Relaxed) & WRITER_WAITING == 0 {
+            self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+        }
+        false
+    }
+
+    pub fn release_read(&self) {
+        self.state.fetch_sub(1, Ordering::Release);
+    }
+
+    pub fn release_write(&self) {
+        // Leave `WRITER_WAITING` alone, someone might have set it while we held the lock.
+        self.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+impl Default for RwLockState {
+    fn default() -> Self {
+        RwLockState::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readers_share() {
+        let lock = RwLockState::new();
+        assert!(lock.try_acquire_read());
+        assert!(lock.try_acquire_read());
+        assert!(!lock.try_acquire_write());
+
+        lock.release_read();
+        lock.release_read();
+        assert!(lock.try_acquire_write());
+    }
+
+    #[test]
+    fn waiting_writer_keeps_new_readers_out() {
+        let lock = RwLockState::new();
+        assert!(lock.try_acquire_read());
+
+        assert!(!lock.try_acquire_write_or_wait());
+        assert_eq!(lock.state.load(Ordering::Relaxed), WRITER_WAITING | 1);
+        assert!(!lock.try_acquire_read());
+
+        lock.release_read();
+        assert!(lock.try_acquire_write_or_wait());
+        assert_eq!(lock.state.load(Ordering::Relaxed), WRITER);
+
+        lock.release_write();
+        assert!(lock.try_acquire_read());
+    }
+
+    #[test]
+    fn writer_release_keeps_waiting_bit() {
+        let lock = RwLockState::new();
+        assert!(lock.try_acquire_write_or_wait());
+
+        // A second writer shows up while the first holds the lock.
+        assert!(!lock.try_acquire_write_or_wait());
+        lock.release_write();
+        assert_eq!(lock.state.load(Ordering::Relaxed), WRITER_WAITING);
+        assert!(!lock.try_acquire_read());
+
+        assert!(lock.try_acquire_write_or_wait());
+        lock.release_write();
+        assert_eq!(lock.state.load(Ordering::Relaxed), 0);
+        assert!(lock.try_acquire_read());
+    }
+}
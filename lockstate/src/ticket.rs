@@ -0,0 +1,96 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Tickets for a lock that is handed out in the order it was asked for.
+pub struct TicketLockState {
+    next: AtomicU32,
+    serving: AtomicU32,
+}
+
+impl TicketLockState {
+    pub const fn new() -> Self {
+        TicketLockState {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+        }
+    }
+
+    /// Get in line, the lock is ours once `is_serving` the ticket.
+    pub fn take_ticket(&self) -> u32 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Only take a ticket if it would be served right away.
+    pub fn try_take_ticket(&self) -> bool {
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.next.compare_exchange(serving, serving.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    pub fn is_serving(&self, ticket: u32) -> bool {
+        self.serving.load(Ordering::Acquire) == ticket
+    }
+
+    /// Only the holder changes `serving`.
+    pub fn release(&self) {
+        self.serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl Default for TicketLockState {
+    fn default() -> Self {
+        TicketLockState::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn served_in_order() {
+        let lock = TicketLockState::new();
+        let first = lock.take_ticket();
+        let second = lock.take_ticket();
+        let third = lock.take_ticket();
+
+        assert!(lock.is_serving(first));
+        assert!(!lock.is_serving(second));
+        assert!(!lock.is_serving(third));
+
+        lock.release();
+        assert!(lock.is_serving(second));
+        assert!(!lock.is_serving(third));
+
+        lock.release();
+        assert!(lock.is_serving(third));
+    }
+
+    #[test]
+    fn try_lock_does_not_jump_the_queue() {
+        let lock = TicketLockState::new();
+        assert!(lock.try_take_ticket());
+        assert!(!lock.try_take_ticket());
+
+        let waiting = lock.take_ticket();
+        lock.release();
+        assert!(lock.is_serving(waiting));
+        assert!(!lock.try_take_ticket());
+
+        lock.release();
+        assert!(lock.try_take_ticket());
+    }
+
+    #[test]
+    fn tickets_wrap_around() {
+        let lock = TicketLockState {
+            next: AtomicU32::new(u32::MAX),
+            serving: AtomicU32::new(u32::MAX),
+        };
+        let first = lock.take_ticket();
+        let second = lock.take_ticket();
+        assert_eq!(second, 0);
+
+        assert!(lock.is_serving(first));
+        lock.release();
+        assert!(lock.is_serving(second));
+    }
+}
@@ -2,9 +2,11 @@ use anyhow::Result;
 use build::build;
 use clap::{App, AppSettings, SubCommand};
 use run::run;
+use test::test;
 
 mod build;
 mod run;
+mod test;
 
 //TODO add clean subcommand
 //TODO add fmt subcommand
@@ -19,6 +21,9 @@ fn main() -> Result<()> {
         .subcommand(
             SubCommand::with_name("run").about("Builds then runs the disk image in qemu")
         )
+        .subcommand(
+            SubCommand::with_name("test").about("Runs the tests of the crates that build for the host")
+        )
         .get_matches();
 
     if let Some(_matches) = matches.subcommand_matches("build") {
@@ -27,6 +32,8 @@ fn main() -> Result<()> {
         build()?;
         println!("Running...");
         run()?;
+    } else if let Some(_matches) = matches.subcommand_matches("test") {
+        test()?;
     }
 
     Ok(())
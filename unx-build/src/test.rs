@@ -0,0 +1,21 @@
+use anyhow::Result;
+use std::process::Command;
+
+/// Crates that don't need the kernel target, so their tests can run on the host.
+const HOST_CRATES: &[&str] = &["datetime", "lockstate"];
+
+pub fn test() -> Result<()> {
+    for krate in HOST_CRATES {
+        println!("Testing {}...", krate);
+        println!("Running `cargo test`");
+
+        let status = Command::new("cargo")
+            .arg("test")
+            .current_dir(krate)
+            .status()?;
+
+        anyhow::ensure!(status.success(), "tests were unsuccessful");
+    }
+
+    Ok(())
+}